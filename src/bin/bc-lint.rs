@@ -0,0 +1,250 @@
+//! アセットのルート以下にある全ユニット・全敵のモデルとアニメーションを読み込んでチェックする
+//!
+//! ```text
+//! bc-lint [--json] [--index PATH] [ROOT]
+//! ```
+//! ROOTの既定値は`assets/org`。エラーが1つでもあれば終了コード1で終わる。
+//! 対象のユニットは毎回ROOTを走査して選ぶ。
+//! `--index`を付けるとPATHに保存した一覧を使い、ROOTやファイルが変わっていれば走査して保存し直す。
+
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use battle_cats::database::{
+    animation::{state_gen::Maanim, AnimSelector, UnitSelector},
+    catalog::UnitCatalog,
+    error::Error,
+    source::{AssetSource, DirSource},
+    validate::{self, Severity, Target},
+    Imgcut, Mamodels,
+};
+use serde::Serialize;
+
+#[derive(Serialize)]
+struct Entry {
+    severity: &'static str,
+    file: String,
+    message: String,
+}
+
+/// ユニット1形態(敵1体)分の結果
+#[derive(Serialize)]
+struct UnitReport {
+    target: String,
+    files: usize,
+    errors: usize,
+    warnings: usize,
+    entries: Vec<Entry>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    root: PathBuf,
+    units: usize,
+    files: usize,
+    errors: usize,
+    warnings: usize,
+    reports: Vec<UnitReport>,
+}
+
+impl UnitReport {
+    fn push(&mut self, severity: Severity, file: String, message: String) {
+        match severity {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+        }
+        self.entries.push(Entry {
+            severity: match severity {
+                Severity::Error => "error",
+                Severity::Warning => "warning",
+            },
+            file,
+            message,
+        });
+    }
+
+    /// ファイルが無ければNone、読み込みに失敗したらエラーとして記録する
+    fn parse<T>(
+        &mut self,
+        source: &dyn AssetSource,
+        file: String,
+        parser: impl FnOnce(Cursor<Vec<u8>>) -> Result<T, Error>,
+    ) -> Option<T> {
+        if !source.is_file(Path::new(&file)) {
+            return None;
+        }
+        self.files += 1;
+        match source
+            .read(Path::new(&file))
+            .and_then(|bytes| parser(Cursor::new(bytes)))
+        {
+            Ok(v) => Some(v),
+            Err(err) => {
+                self.push(Severity::Error, file, err.to_string().replace('\n', ": "));
+                None
+            }
+        }
+    }
+}
+
+fn lint(
+    source: &dyn AssetSource,
+    selector: UnitSelector,
+    anim_selectors: &[AnimSelector],
+) -> UnitReport {
+    let mut report = UnitReport {
+        target: selector.path(),
+        files: 0,
+        errors: 0,
+        warnings: 0,
+        entries: Vec::new(),
+    };
+    let models = report.parse(source, selector.mamodels(), Mamodels::from_reader);
+    let imgcuts = report
+        .parse(source, selector.imgcuts(), Imgcut::from_reader)
+        .map(|(_, v)| v);
+    let mut anim_files = Vec::new();
+    let mut anims = Vec::new();
+    for &anim_selector in anim_selectors {
+        let file = selector.maanim(anim_selector);
+        if let Some(anim) = report.parse(source, file.clone(), Maanim::from_reader) {
+            anim_files.push(file);
+            anims.push(anim);
+        }
+    }
+
+    let Some(models) = models else {
+        if report.files > 0 && report.errors == 0 {
+            report.push(Severity::Error, selector.mamodels(), "mamodelが無い".into());
+        }
+        return report;
+    };
+    let imgcuts = imgcuts.unwrap_or_else(|| {
+        // 読めなかったときのエラーはparseで記録済み
+        if !source.is_file(Path::new(&selector.imgcuts())) {
+            report.push(Severity::Error, selector.imgcuts(), "imgcutが無い".into());
+        }
+        Vec::new()
+    });
+    for diagnostic in validate::validate(&models, &imgcuts, &anims) {
+        let file = match diagnostic.target {
+            Target::Anim { anim, .. } => anim_files[anim].clone(),
+            _ => selector.mamodels(),
+        };
+        report.push(
+            diagnostic.severity,
+            file,
+            format!("{}: {}", diagnostic.target, diagnostic.message),
+        );
+    }
+    report
+}
+
+fn print_table(report: &Report) {
+    println!(
+        "{:<16}{:>8}{:>8}{:>10}",
+        "target", "files", "errors", "warnings"
+    );
+    for unit in report
+        .reports
+        .iter()
+        .filter(|unit| unit.errors + unit.warnings > 0)
+    {
+        println!(
+            "{:<16}{:>8}{:>8}{:>10}",
+            unit.target, unit.files, unit.errors, unit.warnings
+        );
+        for entry in &unit.entries {
+            println!("    [{}] {}: {}", entry.severity, entry.file, entry.message);
+        }
+    }
+    println!(
+        "total: {} units, {} files, {} errors, {} warnings",
+        report.units, report.files, report.errors, report.warnings
+    );
+}
+
+fn main() -> ExitCode {
+    let mut json = false;
+    let mut root = PathBuf::from("assets/org");
+    let mut index = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--index" => match args.next() {
+                Some(path) => index = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("--indexの値が無い");
+                    return ExitCode::from(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("usage: bc-lint [--json] [--index PATH] [ROOT]");
+                return ExitCode::SUCCESS;
+            }
+            _ => root = PathBuf::from(arg),
+        }
+    }
+    if !root.is_dir() {
+        eprintln!("{}: ディレクトリが見つからない", root.display());
+        return ExitCode::from(2);
+    }
+
+    let source = DirSource::new(&root);
+    let catalog = match &index {
+        Some(index) => UnitCatalog::load_or_scan(&source, index),
+        None => UnitCatalog::scan(&source),
+    };
+    let reports: Vec<UnitReport> = catalog
+        .iter()
+        .map(|(selector, anims)| lint(&source, selector, anims))
+        .filter(|report| report.files > 0)
+        .collect();
+    let report = Report {
+        units: reports.len(),
+        files: reports.iter().map(|r| r.files).sum(),
+        errors: reports.iter().map(|r| r.errors).sum(),
+        warnings: reports.iter().map(|r| r.warnings).sum(),
+        root,
+        reports,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    } else {
+        print_table(&report);
+    }
+    if report.errors > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use battle_cats::database::source::MemorySource;
+
+    const MAMODEL: &str = "[modelanim:model2]\n1\n1\n\
+        -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n1000,3600,1000\n";
+
+    #[test]
+    fn broken_imgcut() {
+        let selector = UnitSelector::Enemy(2);
+        let mut source = MemorySource::new();
+        source.insert(selector.mamodels(), MAMODEL);
+        let report = lint(&source, selector, &[]);
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.entries[0].message, "imgcutが無い");
+
+        // 壊れたimgcutは読めなかったエラーだけにする
+        source.insert(selector.imgcuts(), "[imgcut]\n0\n002_e.png\n1\n0,0,x,8\n");
+        let report = lint(&source, selector, &[]);
+        assert_eq!(report.errors, 1);
+        assert_eq!(report.entries[0].file, selector.imgcuts());
+        assert!(report.entries[0].message.contains("width"));
+    }
+}
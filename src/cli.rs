@@ -0,0 +1,348 @@
+//! ウィンドウを開かずに実行するサブコマンド
+//!
+//! ```text
+//! battle_cats render (--unit ID [--form f|c|s] | --enemy ID) [--anim ANIM] [--frame N]
+//!                    [--scale S] [--margin PX] [--background RRGGBB[AA]] OUT.png
+//! battle_cats export (--unit ID [--form f|c|s] | --enemy ID) [--anim ANIM]... [--fps F]
+//!                    [--loops N] [--no-crop] [--scale S] [--margin PX] [--background RRGGBB[AA]]
+//!                    OUT.(gif|png|webp)
+//! ```
+//! exportのOUTに`{anim}`を含めると、アニメーションごとに名前を置き換えて書き出す
+//! (--animが無ければwalk、idle、attack、hitbackの4つ)。
+//! データの場所は環境変数で切り替える(BcAssetSource::from_env)。
+
+use std::process::ExitCode;
+
+use battle_cats::database::{
+    animation::{
+        export::{export, ExportFormat, ExportOptions},
+        raster::{HeadlessUnit, View},
+        state_gen::from_data::StateGenerator,
+        AnimSelector, UnitForm, UnitSelector,
+    },
+    error::{Error, ErrorKind},
+    source::BcAssetSource,
+};
+
+const USAGE: &str = "usage: battle_cats render (--unit ID [--form f|c|s] | --enemy ID) \
+    [--anim ANIM] [--frame N] [--scale S] [--margin PX] [--background RRGGBB[AA]] OUT.png
+       battle_cats export (--unit ID [--form f|c|s] | --enemy ID) [--anim ANIM]... \
+    [--fps F] [--loops N] [--no-crop] [--scale S] [--margin PX] [--background RRGGBB[AA]] \
+    OUT.(gif|png|webp)";
+
+/// OUTに含めるとアニメーションの名前に置き換える
+const ANIM_PLACEHOLDER: &str = "{anim}";
+
+/// renderの引数
+#[derive(Debug, PartialEq)]
+struct RenderArgs {
+    selector: UnitSelector,
+    anim: AnimSelector,
+    frame: f32,
+    scale: f32,
+    margin: u32,
+    background: [u8; 4],
+    out: String,
+}
+
+/// exportの引数
+#[derive(Debug, PartialEq)]
+struct ExportArgs {
+    selector: UnitSelector,
+    anims: Vec<AnimSelector>,
+    options: ExportOptions,
+    out: String,
+}
+
+fn parse_color(s: &str) -> Option<[u8; 4]> {
+    if s == "transparent" {
+        return Some([0; 4]);
+    }
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if !matches!(s.len(), 6 | 8) || !s.is_ascii() {
+        return None;
+    }
+    let mut color = [255; 4];
+    for (i, c) in color.iter_mut().enumerate().take(s.len() / 2) {
+        *c = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(color)
+}
+
+/// renderとexportで共通の引数
+struct CommonArgs {
+    selector: UnitSelector,
+    anims: Vec<AnimSelector>,
+    scale: f32,
+    margin: u32,
+    background: [u8; 4],
+    out: String,
+}
+
+/// サブコマンド固有のオプションはextraに渡す
+/// extraは(オプション名、値を取り出す関数)を受け取って、知らないオプションならfalseを返す
+fn parse_common(
+    args: &[String],
+    mut extra: impl FnMut(&str, &mut dyn FnMut() -> Result<String, String>) -> Result<bool, String>,
+) -> Result<CommonArgs, String> {
+    let mut unit = None;
+    let mut enemy = None;
+    let mut form = UnitForm::Form1;
+    let mut common = CommonArgs {
+        selector: UnitSelector::Enemy(0),
+        anims: Vec::new(),
+        scale: 1.,
+        margin: 4,
+        background: [0; 4],
+        out: String::new(),
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("{arg}の値が無い"))
+        };
+        let invalid = |value: &str| format!("{arg}の値({value})が正しくない");
+        match arg.as_str() {
+            "--unit" => {
+                let v = value()?;
+                unit = Some(v.parse::<u16>().map_err(|_| invalid(&v))?);
+            }
+            "--enemy" => {
+                let v = value()?;
+                enemy = Some(v.parse::<u16>().map_err(|_| invalid(&v))?);
+            }
+            "--form" => {
+                let v = value()?;
+                let mut chars = v.chars();
+                form = match (chars.next().and_then(UnitForm::from_char), chars.next()) {
+                    (Some(form), None) => form,
+                    _ => return Err(invalid(&v)),
+                };
+            }
+            "--anim" => {
+                let v = value()?;
+                common.anims.push(AnimSelector::from_name(&v).ok_or_else(|| invalid(&v))?);
+            }
+            "--scale" => {
+                let v = value()?;
+                common.scale = v.parse().ok().filter(|&s: &f32| s > 0.).ok_or_else(|| invalid(&v))?;
+            }
+            "--margin" => {
+                let v = value()?;
+                common.margin = v.parse().map_err(|_| invalid(&v))?;
+            }
+            "--background" => {
+                let v = value()?;
+                common.background = parse_color(&v).ok_or_else(|| invalid(&v))?;
+            }
+            out if !out.starts_with('-') && common.out.is_empty() => common.out = out.into(),
+            option => {
+                if !extra(option, &mut value)? {
+                    return Err(format!("{arg}は使えない"));
+                }
+            }
+        }
+    }
+    common.selector = match (unit, enemy) {
+        (Some(id), None) => UnitSelector::Unit((id, form)),
+        (None, Some(id)) => UnitSelector::Enemy(id),
+        _ => return Err("--unitか--enemyのどちらか1つを指定する".into()),
+    };
+    if common.out.is_empty() {
+        return Err("出力するファイルが無い".into());
+    }
+    Ok(common)
+}
+
+fn parse_render(args: &[String]) -> Result<RenderArgs, String> {
+    let mut frame = 0.;
+    let common = parse_common(args, |option, value| match option {
+        "--frame" => {
+            let v = value()?;
+            frame = v.parse().map_err(|_| format!("{option}の値({v})が正しくない"))?;
+            Ok(true)
+        }
+        _ => Ok(false),
+    })?;
+    let anim = match common.anims[..] {
+        [] => AnimSelector::Walk,
+        [anim] => anim,
+        _ => return Err("renderの--animは1つだけ".into()),
+    };
+    Ok(RenderArgs {
+        selector: common.selector,
+        anim,
+        frame,
+        scale: common.scale,
+        margin: common.margin,
+        background: common.background,
+        out: common.out,
+    })
+}
+
+fn parse_export(args: &[String]) -> Result<ExportArgs, String> {
+    let mut options = ExportOptions::default();
+    let common = parse_common(args, |option, value| {
+        match option {
+            "--fps" => {
+                let v = value()?;
+                options.fps = v
+                    .parse()
+                    .ok()
+                    .filter(|&fps: &f32| fps > 0.)
+                    .ok_or_else(|| format!("{option}の値({v})が正しくない"))?;
+            }
+            "--loops" => {
+                let v = value()?;
+                options.loops = v
+                    .parse()
+                    .ok()
+                    .filter(|&loops: &u32| loops > 0)
+                    .ok_or_else(|| format!("{option}の値({v})が正しくない"))?;
+            }
+            "--no-crop" => options.crop = false,
+            _ => return Ok(false),
+        }
+        Ok(true)
+    })?;
+    options.scale = common.scale;
+    options.margin = common.margin;
+    options.background = common.background;
+    if ExportFormat::from_path(&common.out).is_none() {
+        return Err(format!("{}の拡張子はgif、png、apng、webpのどれか", common.out));
+    }
+    let mut anims = common.anims;
+    if common.out.contains(ANIM_PLACEHOLDER) {
+        if anims.is_empty() {
+            anims = vec![
+                AnimSelector::Walk,
+                AnimSelector::Idle,
+                AnimSelector::Attack,
+                AnimSelector::HitBack,
+            ];
+        }
+    } else if anims.len() > 1 {
+        return Err(format!("--animを複数指定するときはOUTに{ANIM_PLACEHOLDER}を含める"));
+    } else if anims.is_empty() {
+        anims.push(AnimSelector::Walk);
+    }
+    Ok(ExportArgs {
+        selector: common.selector,
+        anims,
+        options,
+        out: common.out,
+    })
+}
+
+fn render(args: &RenderArgs) -> Result<(), Error> {
+    let source = BcAssetSource::from_env();
+    let unit = HeadlessUnit::load(&*source, args.selector)?;
+    let maanim = args.selector.load_maanim(&*source, args.anim)?;
+    let state = StateGenerator::from_anim(maanim, &unit.image.mamodels).state_at(args.frame);
+    let bounds = unit.bounds(&state).unwrap_or_default();
+    let view = View {
+        background: args.background,
+        ..View::fit(bounds, args.scale, args.margin)
+    };
+    unit.render(&state, &view)
+        .save(&args.out)
+        .map_err(|e| Error::new(ErrorKind::IOError, e).with_path(&args.out))
+}
+
+fn export_anims(args: &ExportArgs) -> Result<(), Error> {
+    let source = BcAssetSource::from_env();
+    let unit = HeadlessUnit::load(&*source, args.selector)?;
+    for &anim in &args.anims {
+        let maanim = args.selector.load_maanim(&*source, anim)?;
+        let out = args.out.replace(ANIM_PLACEHOLDER, anim.name());
+        export(&unit, maanim, &args.options, &out)?;
+    }
+    Ok(())
+}
+
+/// サブコマンドを実行する(argsはプログラム名を除いたもの)
+pub fn run(args: &[String]) -> ExitCode {
+    let result = match args[0].as_str() {
+        "render" => parse_render(&args[1..]).map(|args| render(&args)),
+        "export" => parse_export(&args[1..]).map(|args| export_anims(&args)),
+        "-h" | "--help" => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        command => Err(format!("{command}というサブコマンドは無い")),
+    };
+    match result {
+        Ok(Ok(())) => ExitCode::SUCCESS,
+        Ok(Err(err)) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+        Err(msg) => {
+            eprintln!("{msg}\n{USAGE}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn render_args() {
+        let render = parse_render(&args("--unit 693 --form c --anim attack --frame 12 out.png")).unwrap();
+        assert_eq!(render.selector, UnitSelector::Unit((693, UnitForm::Form2)));
+        assert_eq!((render.anim, render.frame, render.out.as_str()), (AnimSelector::Attack, 12., "out.png"));
+        assert_eq!(render.background, [0; 4]);
+
+        let render = parse_render(&args("a.png --enemy 3 --background 336699 --scale 2")).unwrap();
+        assert_eq!(render.selector, UnitSelector::Enemy(3));
+        assert_eq!((render.background, render.scale), ([0x33, 0x66, 0x99, 255], 2.));
+
+        for bad in [
+            "--unit 1 --enemy 2 a.png",
+            "--unit 1",
+            "--unit 1 --form x a.png",
+            "--unit 1 --anim jump a.png",
+            "--unit 1 --scale 0 a.png",
+            "--unit 1 a.png b.png",
+            "--unit",
+        ] {
+            assert!(parse_render(&args(bad)).is_err(), "{bad}");
+        }
+        assert_eq!(parse_color("#00000080"), Some([0, 0, 0, 128]));
+        assert_eq!(parse_color("12345"), None);
+    }
+
+    #[test]
+    fn export_args() {
+        let export = parse_export(&args("--enemy 4 --fps 15 --loops 2 --no-crop {anim}.webp")).unwrap();
+        assert_eq!(export.selector, UnitSelector::Enemy(4));
+        assert_eq!(
+            export.anims,
+            [AnimSelector::Walk, AnimSelector::Idle, AnimSelector::Attack, AnimSelector::HitBack]
+        );
+        assert_eq!((export.options.fps, export.options.loops, export.options.crop), (15., 2, false));
+
+        let export = parse_export(&args("--unit 1 --anim attack --background ffffff --scale 2 a.gif")).unwrap();
+        assert_eq!(export.anims, [AnimSelector::Attack]);
+        assert_eq!((export.options.background, export.options.scale), ([255; 4], 2.));
+        assert!(export.options.crop);
+
+        for bad in [
+            "--unit 1 a.jpg",
+            "--unit 1 --anim walk --anim idle a.gif",
+            "--unit 1 --fps 0 a.gif",
+            "--unit 1 --loops 0 a.gif",
+            "--unit 1 --frame 3 a.gif",
+        ] {
+            assert!(parse_export(&args(bad)).is_err(), "{bad}");
+        }
+    }
+}
@@ -1,18 +1,25 @@
 #![allow(unused)]
 pub mod animation;
+pub mod asset_loader;
 pub mod spawn;
 pub mod image_handle;
-use bevy::prelude::*;
+pub mod validate;
+pub mod pack;
+pub mod source;
+pub mod catalog;
+use bevy::{prelude::*, reflect::TypeUuid};
 
 
 pub struct BattleCatsDB {}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Imgcut {
     x: u32,
     y: u32,
     width: u32,
     height: u32,
+    /// 5列目の名前(無い場合もある)
+    name: Option<String>,
 }
 
 impl Imgcut {
@@ -34,7 +41,7 @@ impl Imgcut {
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
 pub enum GlowType {
     #[default]
     None,
@@ -43,6 +50,17 @@ pub enum GlowType {
     Inverse,
 }
 
+impl From<GlowType> for i32 {
+    fn from(value: GlowType) -> Self {
+        match value {
+            GlowType::None => 0,
+            GlowType::Black => 1,
+            GlowType::White => 2,
+            GlowType::Inverse => -1,
+        }
+    }
+}
+
 impl From<i32> for GlowType {
     fn from(value: i32) -> Self {
         match value {
@@ -54,9 +72,22 @@ impl From<i32> for GlowType {
     }
 }
 
-#[derive(Debug, Clone)]
+/// imgcutファイル1つ分のデータ
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "b0c437d1-4a8d-4ca9-bc53-3034131f45b5"]
+pub struct ImgcutSheet {
+    /// imgcutに書かれている画像ファイル名
+    pub filename: String,
+    /// 切り出し元の画像
+    pub texture: Handle<Image>,
+    pub imgcuts: Vec<Imgcut>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Mamodel {
     parent: i32,
+    /// 参照するスプライトシート(モデル)の番号
+    id: i32,
     imgind: i32,
     zorder: i32,
     posx: i32,
@@ -68,6 +99,12 @@ pub struct Mamodel {
     angle: i32,
     opacity: i32,
     glow: GlowType,
+    /// ファイルに書かれているglowの値(3もBlackになるので書き戻す用に残す)
+    glow_value: i32,
+    /// パーツの名前(コメント)
+    name: Option<String>,
+    /// 名前以降の未知の列(そのまま書き戻す)
+    extra: Vec<String>,
 }
 
 
@@ -76,65 +113,150 @@ use error::ErrorKind;
 
 
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Lines};
+use std::io::{self, BufRead, BufReader, Lines, Write};
 use std::path::Path;
 use std::str::{FromStr, Split};
 
 use crate::material::Glow1Material;
+use source::AssetSource;
+
+/// 1行ずつ読み出す
+/// 先頭のBOM、CRLFの改行、行末の空白は取り除く
+pub(crate) struct LineReader<R> {
+    lines: Lines<R>,
+    /// エラー表示用のファイル形式名
+    format: &'static str,
+    /// 最後に読んだ行の行番号(1始まり)
+    line: usize,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub(crate) fn new(reader: R, format: &'static str) -> Self {
+        Self {
+            lines: reader.lines(),
+            format,
+            line: 0,
+        }
+    }
+
+    /// 最後に読んだ行の位置情報をエラーに付ける
+    fn locate(&self, err: error::Error) -> error::Error {
+        err.at_line(self.format, self.line)
+    }
+
+    /// 最後に読んだ行をカンマで区切る
+    fn fields<'a>(&self, s: &'a str) -> Fields<'a> {
+        Fields {
+            split: s.split(','),
+            format: self.format,
+            line: self.line,
+            index: 0,
+            name: "",
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for LineReader<R> {
+    type Item = io::Result<String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let first = self.line == 0;
+        let line = self.lines.next()?;
+        self.line += 1;
+        Some(line.map(|s| {
+            let s = if first { s.trim_start_matches('\u{feff}') } else { &s };
+            s.trim_end().to_owned()
+        }))
+    }
+}
+
+/// 1行分のカンマ区切りの値
+pub(crate) struct Fields<'a> {
+    split: Split<'a, char>,
+    format: &'static str,
+    line: usize,
+    /// 次に読む列の番号(1始まり)
+    index: usize,
+    /// 最後に読んだ列の名前
+    name: &'static str,
+}
+
+impl<'a> Fields<'a> {
+    /// 値を読まずに1列進める
+    fn skip(&mut self, name: &'static str) -> Option<&'a str> {
+        self.index += 1;
+        self.name = name;
+        self.split.next()
+    }
+
+    /// 最後に読んだ列の位置情報をエラーに付ける
+    fn locate(&self, err: error::Error) -> error::Error {
+        err.at_line(self.format, self.line)
+            .at_field(self.index, self.name)
+    }
+
+    /// 残りの列をそのまま取り出す
+    fn rest(self) -> Vec<String> {
+        self.split.map(str::to_owned).collect()
+    }
+}
 
 /// io関連のエラーは引継ぎ、関数fの戻り値がfalseだったらFileFormatErrorとする
+/// 読んだ行を返す
 fn consume_buf(
-    itr: &mut Lines<BufReader<File>>,
+    itr: &mut LineReader<impl BufRead>,
     f: impl FnOnce(&String) -> bool,
-) -> Result<(), error::Error> {
-    if let Some(buf) = itr.next() {
-        let s = buf?;
-        // println!("{}", s);
-        // println!("{}", s.find("[imgcut]").is_some());
-        if f(&s) {
-            // println!("ok");
-            Ok(())
-        } else {
-            // println!("error");
-            Err(error::ErrorKind::FileFormatError.into())
-        }
+) -> Result<String, error::Error> {
+    let s = get_string(itr)?;
+    if f(&s) {
+        Ok(s)
     } else {
-        // println!("none");
-        Err(error::ErrorKind::FileFormatError.into())
+        Err(itr.locate(error::Error::new(
+            ErrorKind::FileFormatError,
+            format!("予期しない行: {s:?}"),
+        )))
     }
 }
 
-fn get_string(itr: &mut Lines<BufReader<File>>) -> Result<String, error::Error> {
+fn get_string(itr: &mut LineReader<impl BufRead>) -> Result<String, error::Error> {
     match itr.next() {
-        Some(buf) => buf.map_err(|err| err.into()),
-        None => Err(error::ErrorKind::FileFormatError.into()),
+        Some(buf) => buf.map_err(|err| itr.locate(err.into())),
+        None => Err(itr.locate(error::Error::new(
+            ErrorKind::FileFormatError,
+            "ファイルの終端に到達",
+        ))),
     }
 }
 
 fn get_next_line<T: FromStr<Err = impl Into<Box<dyn std::error::Error + Send + Sync>>>>(
-    itr: &mut Lines<BufReader<File>>,
+    itr: &mut LineReader<impl BufRead>,
+    name: &'static str,
 ) -> Result<T, error::Error> {
-    match itr.next() {
-        Some(buf) => {
-            let s = buf?;
-            s.parse()
-                .map_err(|err| error::Error::new(ErrorKind::FileFormatError, err))
-        }
-        None => Err(error::Error::new(
-            ErrorKind::FileFormatError,
-            "ファイルの終端に到達",
-        )),
-    }
+    let s = get_string(itr)?;
+    let mut fields = itr.fields(&s);
+    get_next(&mut fields, name)
 }
 
-const ASSET_PATH: &str = "assets";
-const BC_ASSET_PATH: &str = "org";
-
 impl Imgcut {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<(String, Vec<Self>), error::Error> {
-        let f = File::open(Path::new(ASSET_PATH).join(path))?;
-        let reader = BufReader::new(f);
-        let mut itr = reader.lines();
+    pub fn load<P: AsRef<Path>>(
+        source: &dyn AssetSource,
+        path: P,
+    ) -> Result<(String, Vec<Self>), error::Error> {
+        let path = path.as_ref();
+        source
+            .read(path)
+            .and_then(|bytes| Self::from_reader(bytes.as_slice()))
+            .map_err(|err| err.with_path(path))
+    }
+
+    /// (画像ファイル名, 切り出し範囲)を返すのでFromStrは実装しない
+    #[allow(clippy::should_implement_trait)]
+    pub fn from_str(s: &str) -> Result<(String, Vec<Self>), error::Error> {
+        Self::from_reader(s.as_bytes())
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<(String, Vec<Self>), error::Error> {
+        let mut itr = LineReader::new(reader, "imgcut");
 
         // println!("get header");
         consume_buf(&mut itr, |s| s.starts_with("[imgcut]"))?;
@@ -144,51 +266,62 @@ impl Imgcut {
         // println!("get filename");
         let filename = get_string(&mut itr)?;
         // println!("get amount");
-        let amount = get_string(&mut itr)?
-            .parse::<usize>()
-            .map_err(|_| error::Error::from(error::ErrorKind::FileFormatError))?;
+        let amount = get_next_line::<usize>(&mut itr, "amount")?;
         let mut v = Vec::with_capacity(amount);
         for _ in 0..amount {
             let s = get_string(&mut itr)?;
-            let mut split = s.split(',');
+            let mut split = itr.fields(&s);
             v.push(Imgcut {
-                x: split
-                    .next()
-                    .ok_or(error::Error::from_kind(ErrorKind::FileFormatError))?
-                    .parse()
-                    .map_err(|err| error::Error::new(ErrorKind::FileFormatError, err))?,
-                y: split
-                    .next()
-                    .ok_or(error::Error::from_kind(ErrorKind::FileFormatError))?
-                    .parse()
-                    .map_err(|err| error::Error::new(ErrorKind::FileFormatError, err))?,
-                width: split
-                    .next()
-                    .ok_or(error::Error::from_kind(ErrorKind::FileFormatError))?
-                    .parse()
-                    .map_err(|err| error::Error::new(ErrorKind::FileFormatError, err))?,
-                height: split
-                    .next()
-                    .ok_or(error::Error::from_kind(ErrorKind::FileFormatError))?
-                    .parse()
-                    .map_err(|err| error::Error::new(ErrorKind::FileFormatError, err))?,
+                x: get_next(&mut split, "x")?,
+                y: get_next(&mut split, "y")?,
+                width: get_next(&mut split, "width")?,
+                height: get_next(&mut split, "height")?,
+                name: split.skip("name").map(str::to_owned),
             });
         }
         Ok((filename, v))
     }
+
+    /// 読み込んだときと同じ形式で書き出す
+    pub fn write_to<W: Write>(writer: &mut W, filename: &str, imgcuts: &[Self]) -> io::Result<()> {
+        writeln!(writer, "[imgcut]")?;
+        writeln!(writer, "0")?;
+        writeln!(writer, "{filename}")?;
+        writeln!(writer, "{}", imgcuts.len())?;
+        for imgcut in imgcuts {
+            write!(
+                writer,
+                "{},{},{},{}",
+                imgcut.x, imgcut.y, imgcut.width, imgcut.height
+            )?;
+            if let Some(name) = &imgcut.name {
+                write!(writer, ",{name}")?;
+            }
+            writeln!(writer)?;
+        }
+        Ok(())
+    }
+}
+
+impl ImgcutSheet {
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        Imgcut::write_to(writer, &self.filename, &self.imgcuts)
+    }
 }
 
 fn get_next<T: FromStr<Err = impl Into<Box<dyn std::error::Error + Send + Sync>>>>(
-    itr: &mut Split<char>,
+    itr: &mut Fields,
+    name: &'static str,
 ) -> Result<T, error::Error> {
-    match itr.next() {
+    match itr.skip(name) {
         Some(s) => s
+            .trim()
             .parse()
-            .map_err(|err| error::Error::new(ErrorKind::FileFormatError, err)),
-        None => Err(error::Error::new(
+            .map_err(|err| itr.locate(error::Error::new(ErrorKind::FileFormatError, err))),
+        None => Err(itr.locate(error::Error::new(
             ErrorKind::FileFormatError,
-            "ファイルの終端に到達",
-        )),
+            "値が足りない",
+        ))),
     }
 }
 
@@ -202,63 +335,143 @@ pub enum PartMaterialHandle {
 
 pub use PartMaterialHandle::*;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, TypeUuid)]
+#[uuid = "0595a06d-1134-4f42-bd83-73fb9d801083"]
 pub struct Mamodels {
+    /// 先頭の2行(`[modelanim:model2]`など、そのまま書き戻す)
+    header: [String; 2],
     models: Vec<Mamodel>,
     scale_ratio: u32,
     angle_ratio: u32,
     opacity_ratio: u32,
+    /// 比率の行より後ろの行(そのまま書き戻す)
+    trailing: Vec<String>,
 }
 
 impl Mamodels {
-    fn load<P: AsRef<Path>>(path: P) -> Result<Self, error::Error> {
-        let f = File::open(Path::new(ASSET_PATH).join(path))?;
-        let reader = BufReader::new(f);
-        let mut itr = reader.lines();
-
-        consume_buf(&mut itr, |s| {
-            s.starts_with("[modelanim:model]") || s.starts_with("[modelanim:model2]")
-        })?;
-        consume_buf(&mut itr, |_| true)?;
+    pub fn load<P: AsRef<Path>>(source: &dyn AssetSource, path: P) -> Result<Self, error::Error> {
+        let path = path.as_ref();
+        source
+            .read(path)
+            .and_then(|bytes| Self::from_reader(bytes.as_slice()))
+            .map_err(|err| err.with_path(path))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, error::Error> {
+        let mut itr = LineReader::new(reader, "mamodel");
+
+        let header = [
+            consume_buf(&mut itr, |s| {
+                s.starts_with("[modelanim:model]") || s.starts_with("[modelanim:model2]")
+            })?,
+            get_string(&mut itr)?,
+        ];
 
-        let length = get_next_line::<usize>(&mut itr)?;
+        let length = get_next_line::<usize>(&mut itr, "length")?;
         let mut v = Vec::with_capacity(length);
 
         for _ in 0..length {
             let s = get_string(&mut itr)?;
-            let mut split = s.split(',');
-            v.push(Mamodel {
-                parent: get_next(&mut split)?,
-                imgind: {
-                    split.next();
-                    get_next(&mut split)?
-                },
-                zorder: get_next(&mut split)?,
-                posx: get_next(&mut split)?,
-                posy: get_next(&mut split)?,
-                pivotx: get_next(&mut split)?,
-                pivoty: get_next(&mut split)?,
-                scalex: get_next(&mut split)?,
-                scaley: get_next(&mut split)?,
-                angle: get_next(&mut split)?,
-                opacity: get_next(&mut split)?,
-                glow: get_next::<i32>(&mut split)?.into(),
-            });
+            let mut split = itr.fields(&s);
+            let mut model = Mamodel {
+                parent: get_next(&mut split, "parent")?,
+                id: get_next(&mut split, "id")?,
+                imgind: get_next(&mut split, "imgind")?,
+                zorder: get_next(&mut split, "zorder")?,
+                posx: get_next(&mut split, "posx")?,
+                posy: get_next(&mut split, "posy")?,
+                pivotx: get_next(&mut split, "pivotx")?,
+                pivoty: get_next(&mut split, "pivoty")?,
+                scalex: get_next(&mut split, "scalex")?,
+                scaley: get_next(&mut split, "scaley")?,
+                angle: get_next(&mut split, "angle")?,
+                opacity: get_next(&mut split, "opacity")?,
+                glow: GlowType::None,
+                glow_value: get_next(&mut split, "glow")?,
+                name: split.skip("name").map(str::to_owned),
+                extra: split.rest(),
+            };
+            model.glow = model.glow_value.into();
+            v.push(model);
         }
         let s = get_string(&mut itr)?;
-        let mut split = s.split(',');
-        let scale_ratio: u32 = get_next(&mut split)?;
-        let angle_ratio: u32 = get_next(&mut split)?;
-        let opacity_ratio: u32 = get_next(&mut split)?;
+        let mut split = itr.fields(&s);
+        let scale_ratio: u32 = get_next(&mut split, "scale_ratio")?;
+        let angle_ratio: u32 = get_next(&mut split, "angle_ratio")?;
+        let opacity_ratio: u32 = get_next(&mut split, "opacity_ratio")?;
+        let mut trailing = itr.collect::<Result<Vec<_>, _>>()?;
+        while trailing.last().is_some_and(String::is_empty) {
+            trailing.pop();
+        }
         Ok(Mamodels {
+            header,
             models: v,
             scale_ratio,
             angle_ratio,
             opacity_ratio,
+            trailing,
         })
     }
 }
 
+impl Mamodels {
+    /// 読み込んだときと同じ形式で書き出す
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for s in &self.header {
+            writeln!(writer, "{s}")?;
+        }
+        writeln!(writer, "{}", self.models.len())?;
+        for m in &self.models {
+            write!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{},{}",
+                m.parent,
+                m.id,
+                m.imgind,
+                m.zorder,
+                m.posx,
+                m.posy,
+                m.pivotx,
+                m.pivoty,
+                m.scalex,
+                m.scaley,
+                m.angle,
+                m.opacity,
+                // glowを書き換えていなければ元の値のまま
+                if GlowType::from(m.glow_value) == m.glow {
+                    m.glow_value
+                } else {
+                    i32::from(m.glow)
+                },
+            )?;
+            if let Some(name) = &m.name {
+                write!(writer, ",{name}")?;
+            }
+            for s in &m.extra {
+                write!(writer, ",{s}")?;
+            }
+            writeln!(writer)?;
+        }
+        writeln!(
+            writer,
+            "{},{},{}",
+            self.scale_ratio, self.angle_ratio, self.opacity_ratio
+        )?;
+        for s in &self.trailing {
+            writeln!(writer, "{s}")?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Mamodels {
+    type Err = error::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_reader(s.as_bytes())
+    }
+}
+
 impl Mamodel {
     pub fn get_material(
         &self,
@@ -266,10 +479,9 @@ impl Mamodel {
         materials1: &mut ResMut<Assets<ColorMaterial>>,
         materials2: &mut ResMut<Assets<Glow1Material>>,
     ) -> PartMaterialHandle {
-        if self.glow == GlowType::Black {
-            GlowMaterial(materials2.add(Glow1Material::from(image_handle.clone())))
-        } else {
-            NormalMaterial(materials1.add(ColorMaterial::from(image_handle.clone())))
+        match self.glow {
+            GlowType::None => NormalMaterial(materials1.add(ColorMaterial::from(image_handle.clone()))),
+            glow => GlowMaterial(materials2.add(Glow1Material::new(image_handle.clone(), glow))),
         }
     }
 }
@@ -279,11 +491,12 @@ impl Mamodel {
 #[cfg(test)]
 mod test {
     use super::*;
+    use source::DirSource;
     #[test]
     fn imgcut() {
         println!(
             "{:?}",
-            Imgcut::load("assets/org/unit/697/s/697_s.imgcut").expect("失敗")
+            Imgcut::load(&DirSource::default(), "unit/697/s/697_s.imgcut").expect("失敗")
         )
     }
 
@@ -291,10 +504,170 @@ mod test {
     fn mamodel() {
         println!(
             "{:?}",
-            Mamodels::load("assets/org/unit/697/s/697_s.mamodel").unwrap()
+            Mamodels::load(&DirSource::default(), "unit/697/s/697_s.mamodel").unwrap()
         );
     }
 
+    #[test]
+    fn parse_from_bytes() {
+        let imgcut = b"[imgcut]\n0\n000_f.png\n2\n0,0,32,32,head\n32,0,16,48,body\n";
+        let (filename, imgcuts) = Imgcut::from_reader(&imgcut[..]).unwrap();
+        assert_eq!(filename, "000_f.png");
+        assert_eq!(imgcuts.len(), 2);
+        assert_eq!(imgcuts[1].width, 16);
+
+        let mamodel = b"[modelanim:model2]\n1\n2\n\
+            -1,0,0,0,0,0,0,0,1000,1000,0,1000,0,root\n\
+            0,0,1,1,10,-20,16,16,1000,1000,0,1000,1,head\n\
+            1000,3600,1000\n";
+        let models = Mamodels::from_reader(&mamodel[..]).unwrap();
+        assert_eq!(models.models.len(), 2);
+        assert_eq!(models.models[1].glow, GlowType::Black);
+        assert_eq!(models.angle_ratio, 3600);
+    }
+
+    #[test]
+    fn mamodel_columns() {
+        let models: Mamodels = MAMODEL.parse().unwrap();
+        assert_eq!(models.models[0].id, -1);
+        assert_eq!(models.models[0].name, None);
+        assert_eq!(models.models[1].name.as_deref(), Some("head"));
+        assert_eq!(models.models[2].id, 2);
+        assert_eq!(models.models[2].extra, ["7", "x"]);
+        assert_eq!(models.trailing, ["1", "0,-80,80,-20,0,hitbox"]);
+
+        let mut buf = Vec::new();
+        models.write_to(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), MAMODEL.trim_end_matches('\n').to_owned() + "\n");
+    }
+
+    #[test]
+    fn parse_bom_crlf() {
+        let (filename, imgcuts) =
+            Imgcut::from_str("\u{feff}[imgcut]\r\n0\r\n000_f.png\r\n1\r\n0,0,32,32\r\n\r\n\r\n").unwrap();
+        assert_eq!(filename, "000_f.png");
+        assert_eq!(imgcuts[0].height, 32);
+
+        let models: Mamodels = "\u{feff}[modelanim:model2]\r\n1\r\n1\r\n\
+            -1,0,0,0,0,0,0,0,1000,1000,0,1000,0\r\n\
+            1000,3600,1000\r\n\r\n"
+            .parse()
+            .unwrap();
+        assert_eq!(models.models[0].glow, GlowType::None);
+        assert_eq!(models.opacity_ratio, 1000);
+    }
+
+    const IMGCUT: &str = "[imgcut]\n0\n000_f.png\n2\n0,0,32,32,head\n32,0,16,48\n";
+    const MAMODEL: &str = "[modelanim:model2]\n1\n3\n\
+        -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+        0,0,1,1,10,-20,16,16,1000,-1000,900,500,-1,head\n\
+        1,2,2,2,0,5,4,4,1000,1000,0,1000,2,eye,7,x\n\
+        1000,3600,1000\n\
+        1\n0,-80,80,-20,0,hitbox\n\n";
+    const MAANIM: &str = "[modelanim:animation2]\n1\n2\n\
+        1,11,-1,0,0,\n3\n0,0,0,0\n10,900,2,-3\n20,0,3,0\n\
+        0,4,2,0,0,part\n2\n0,1000,4,-1\n15,0,1,0\n";
+
+    /// 読み込み -> 書き出し -> 読み込みで同じデータになることを確認する
+    fn round_trip(path: &Path, content: &str) -> bool {
+        use animation::state_gen::Maanim;
+        let mut buf = Vec::new();
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("imgcut") => {
+                let (filename, imgcuts) = Imgcut::from_str(content).unwrap();
+                Imgcut::write_to(&mut buf, &filename, &imgcuts).unwrap();
+                let written = Imgcut::from_reader(buf.as_slice()).unwrap();
+                assert_eq!((filename, imgcuts), written, "{}", path.display());
+            }
+            Some("mamodel") => {
+                let models: Mamodels = content.parse().unwrap();
+                models.write_to(&mut buf).unwrap();
+                let written = Mamodels::from_reader(buf.as_slice()).unwrap();
+                assert_eq!(models, written, "{}", path.display());
+            }
+            Some("maanim") => {
+                let anim: Maanim = content.parse().unwrap();
+                anim.write_to(&mut buf).unwrap();
+                let written = Maanim::from_reader(buf.as_slice()).unwrap();
+                assert_eq!(anim, written, "{}", path.display());
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn round_trip_dir(dir: &Path, count: &mut usize) {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.is_dir() {
+                round_trip_dir(&path, count);
+            } else if let Ok(content) = fs::read_to_string(&path) {
+                if round_trip(&path, &content) {
+                    *count += 1;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn write_round_trip() {
+        assert!(round_trip(Path::new("000_f.imgcut"), IMGCUT));
+        assert!(round_trip(Path::new("000_f.mamodel"), MAMODEL));
+        assert!(round_trip(Path::new("000_f00.maanim"), MAANIM));
+
+        // 古い形式の見出しと、Blackとして読むglow=3もそのまま書き戻す
+        let old = "[modelanim:model]\n2\n2\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,1,1,10,-20,16,16,1000,1000,0,1000,3\n\
+            1000,3600,1000\n";
+        assert!(round_trip(Path::new("000_f.mamodel"), old));
+        let models: Mamodels = old.parse().unwrap();
+        assert_eq!(models.models[1].glow, GlowType::Black);
+        let mut buf = Vec::new();
+        models.write_to(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), old);
+
+        let old = "[modelanim:animation]\n2\n1\n0,4,-1,0,0\n1\n0,10,0,0\n";
+        let anim: animation::state_gen::Maanim = old.parse().unwrap();
+        let mut buf = Vec::new();
+        anim.write_to(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), old);
+    }
+
+    /// アセットのディレクトリが必要なので`cargo test -- --ignored`で実行する
+    #[test]
+    #[ignore]
+    fn write_round_trip_assets() {
+        let mut count = 0;
+        round_trip_dir(DirSource::default().root(), &mut count);
+        assert!(count > 0, "書き戻せるファイルが無い");
+    }
+
+    #[test]
+    fn error_location() {
+        use std::error::Error as _;
+        let err = Mamodels::from_str(
+            "[modelanim:model2]\n1\n1\n-1,0,0,0,0,0,x,0,1000,1000,0,1000,0\n1000,3600,1000\n",
+        )
+        .unwrap_err();
+        let location = err.location();
+        assert_eq!(location.format, Some("mamodel"));
+        assert_eq!(location.line, Some(4));
+        assert_eq!(location.field, Some((7, "pivotx")));
+        assert!(err.source().unwrap().is::<std::num::ParseIntError>());
+
+        let err = err.with_path("000_f.mamodel");
+        assert!(err
+            .to_string()
+            .starts_with("000_f.mamodel: mamodel line 4 field 7 'pivotx': "));
+
+        let err = Mamodels::from_str("[modelanim:model2]\n1\n1\n").unwrap_err();
+        assert_eq!(err.location().line, Some(3));
+    }
+
     
 
     use super::animation::{UnitForm, UnitSelector};
@@ -302,15 +675,11 @@ mod test {
     use std::path::Path;
     #[test]
     fn load_maanim() {
-        let unit_path = Path::new("assets/org/unit");
-        let mut counter = 0;
-        for i in 0..=697 {
-            for c in ['f', 's', 'c'] {
-                let path = unit_path.join(format!("{0:>03}/{c}/{0:>03}_{c}.mamodel", i));
-                if let Ok(models) = Mamodels::load(path) {
-                    if let Some(model) = models.models.iter().find(|elem| elem.glow == GlowType::Inverse) {
-                        println!("({i}, {c}): glow: {:?}", model.glow);
-                    }
+        let source = DirSource::default();
+        for selector in catalog::UnitCatalog::scan(&source).selectors() {
+            if let Ok(models) = selector.load_mamodel(&source) {
+                if let Some(model) = models.models.iter().find(|elem| elem.glow == GlowType::Inverse) {
+                    println!("{selector:?}: glow: {:?}", model.glow);
                 }
             }
         }
@@ -319,12 +688,15 @@ mod test {
     
 }
 
-mod error {
+pub mod error {
+    use std::path::{Path, PathBuf};
+
     #[derive(Debug, Clone, Copy)]
     pub enum ErrorKind {
         IOError,
         FileFormatError,
         InvalidNumber,
+        InvalidData,
     }
 
     impl ErrorKind {
@@ -333,10 +705,52 @@ mod error {
                 ErrorKind::IOError => "ファイルを開けなかった",
                 ErrorKind::FileFormatError => "ファイルのフォーマットが正しくない",
                 ErrorKind::InvalidNumber => "無効な数が指定された",
+                ErrorKind::InvalidData => "データの整合性が取れていない",
             }
         }
     }
 
+    /// エラーが起きたファイル内の位置
+    #[derive(Debug, Clone, Default, PartialEq, Eq)]
+    pub struct Location {
+        pub path: Option<PathBuf>,
+        /// ファイル形式名 (imgcut, mamodel, maanim)
+        pub format: Option<&'static str>,
+        /// 行番号(1始まり)
+        pub line: Option<usize>,
+        /// 列番号(1始まり)と期待していた値の名前
+        pub field: Option<(usize, &'static str)>,
+    }
+
+    impl Location {
+        fn is_empty(&self) -> bool {
+            *self == Self::default()
+        }
+    }
+
+    use std::fmt;
+    /// 例: `assets/org/unit/000/f/000_f.mamodel: mamodel line 14 field 7 'pivotx'`
+    impl fmt::Display for Location {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            let mut sep = "";
+            if let Some(path) = &self.path {
+                write!(f, "{}", path.display())?;
+                sep = ": ";
+            }
+            if let Some(line) = self.line {
+                f.write_str(sep)?;
+                if let Some(format) = self.format {
+                    write!(f, "{format} ")?;
+                }
+                write!(f, "line {line}")?;
+                if let Some((index, name)) = self.field {
+                    write!(f, " field {index} '{name}'")?;
+                }
+            }
+            Ok(())
+        }
+    }
+
     use std::error;
     #[derive(Debug)]
     enum _Error {
@@ -346,6 +760,7 @@ mod error {
 
     pub struct Error {
         _error: _Error,
+        location: Location,
     }
 
     impl Error {
@@ -355,12 +770,19 @@ mod error {
         {
             Error {
                 _error: _Error::Custom((kind, error.into())),
+                location: Location::default(),
             }
         }
 
         pub const fn from_kind(kind: ErrorKind) -> Self {
             Error {
                 _error: _Error::Simple(kind),
+                location: Location {
+                    path: None,
+                    format: None,
+                    line: None,
+                    field: None,
+                },
             }
         }
 
@@ -370,11 +792,36 @@ mod error {
                 _Error::Custom(k) => k.0,
             }
         }
+
+        pub fn location(&self) -> &Location {
+            &self.location
+        }
+
+        /// すでにパスが設定されている場合は上書きしない
+        pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+            self.location
+                .path
+                .get_or_insert_with(|| path.as_ref().to_path_buf());
+            self
+        }
+
+        pub fn at_line(mut self, format: &'static str, line: usize) -> Self {
+            self.location.format.get_or_insert(format);
+            self.location.line.get_or_insert(line);
+            self
+        }
+
+        pub fn at_field(mut self, index: usize, name: &'static str) -> Self {
+            self.location.field.get_or_insert((index, name));
+            self
+        }
     }
 
-    use std::fmt;
     impl fmt::Display for Error {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if !self.location.is_empty() {
+                write!(f, "{}: ", self.location)?;
+            }
             match &self._error {
                 _Error::Simple(kind) => f.write_str(kind.msg()),
                 _Error::Custom((kind, source)) => {
@@ -387,6 +834,9 @@ mod error {
 
     impl fmt::Debug for Error {
         fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            if !self.location.is_empty() {
+                write!(f, "{}: ", self.location)?;
+            }
             match &self._error {
                 _Error::Simple(kind) => f.write_str(kind.msg()),
                 _Error::Custom((kind, source)) => {
@@ -401,24 +851,20 @@ mod error {
         fn source(&self) -> Option<&(dyn error::Error + 'static)> {
             match &self._error {
                 _Error::Simple(_) => None,
-                _Error::Custom(c) => c.1.source(),
+                _Error::Custom(c) => Some(c.1.as_ref()),
             }
         }
     }
 
     impl From<ErrorKind> for Error {
         fn from(kind: ErrorKind) -> Self {
-            Error {
-                _error: _Error::Simple(kind),
-            }
+            Error::from_kind(kind)
         }
     }
 
     impl From<std::io::Error> for Error {
         fn from(err: std::io::Error) -> Self {
-            Error {
-                _error: _Error::Custom((ErrorKind::IOError, err.into())),
-            }
+            Error::new(ErrorKind::IOError, err)
         }
     }
 
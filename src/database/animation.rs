@@ -1,13 +1,28 @@
+pub mod batch;
+pub mod export;
+pub mod raster;
+pub mod solver;
 pub mod state_gen;
+pub mod state_machine;
 use crate::material::Glow1Material;
 
-use self::state_gen::{Maanim, StateDiff, StateDiffVal, StateDiffs, from_data::StateGenerator};
+use self::state_gen::{
+    cache::TrackCache, from_data::StateGenerator, Maanim, Modification, StateDiff, StateDiffVal,
+    StateDiffs,
+};
+use self::batch::{update_unit_batch, UnitBatch};
+use self::solver::solve;
+use self::state_machine::{
+    update_state_machine, AnimRequest, AnimStateMachine, AnimationFinished, AnimationLooped,
+    AnimationStarted, FrameMarker,
+};
 
 use super::{*, error::Error};
 use bevy::{
     prelude::*,
+    render::view::VisibilitySystems,
     sprite::{Anchor, MaterialMesh2dBundle, Mesh2dHandle},
-    time::common_conditions::on_timer,
+    transform::TransformSystem,
 };
 
 pub struct BcuAnim;
@@ -26,6 +41,26 @@ pub struct UnitImages {
 #[derive(Clone, Resource)]
 pub struct UnitSpriteIds(Vec<UnitSpriteId>);
 
+/// ユニットの描き方
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Resource)]
+pub enum UnitRenderer {
+    /// パーツごとにエンティティを作る
+    #[default]
+    Parts,
+    /// 1ユニットを動的なメッシュでまとめて描く(batch)
+    Batched,
+}
+
+impl UnitRenderer {
+    /// `BC_RENDERER`が`batch`ならBatched
+    pub fn from_env() -> Self {
+        match std::env::var("BC_RENDERER").as_deref() {
+            Ok("batch") => Self::Batched,
+            _ => Self::Parts,
+        }
+    }
+}
+
 /// 1ユニットの各パーツのスプライトのID
 #[derive(Clone, Resource)]
 pub struct UnitSpriteId {
@@ -45,6 +80,7 @@ pub struct UnitState {
     states: Vec<State>,
 }
 
+use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, time::Duration};
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
@@ -61,14 +97,24 @@ impl From<Imgcut> for Size2d {
         }
     }
 }
+/// スプライトシート(画像とimgcut)1枚分
+#[derive(Clone)]
+pub struct UnitSheet {
+    pub imgcuts: Vec<Imgcut>,
+    pub size: Vec<Size2d>,
+    pub meshes: Vec<Mesh2dHandle>,
+    pub texture: Handle<Image>,
+    /// meshesのUVを計算したときの画像の大きさ
+    pub texture_size: Size2d,
+}
+
 /// 1キャラの画像データ
 #[derive(Clone)]
 pub struct UnitImage {
     pub materials: Vec<PartMaterialHandle>,
     // glow1_image: HashMap<i32, Handle<Image>>,
-    // imgcuts: Vec<Imgcut>,
-    pub size: Vec<Size2d>,
-    pub meshes: Vec<Mesh2dHandle>,
+    /// mamodelのid列とModification::Idの番号順(0が<name>.png、kが<name>_{k:02}.png)
+    pub sheets: Vec<UnitSheet>,
     pub mamodels: Mamodels,
 }
 
@@ -79,41 +125,49 @@ pub struct UnitImage {
 //     maanims: Vec<Maanim>,
 // }
 
+/// 補間できる値は途中のフレームも表せるようにf32で持つ
 #[derive(Clone, Debug, Default)]
 pub struct State {
     parent: i32,
     img: i32,
     zorder: i32,
-    x: i32,
-    y: i32,
-    pivotx: i32,
-    pivoty: i32,
-    scale: i32,
-    scalex: i32,
-    scaley: i32,
-    angle: i32,
-    opacity: i32,
+    x: f32,
+    y: f32,
+    pivotx: f32,
+    pivoty: f32,
+    scale: f32,
+    scalex: f32,
+    scaley: f32,
+    angle: f32,
+    opacity: f32,
+    /// 画像だけを引き伸ばす倍率(子パーツには伝わらない)
+    extendx: f32,
+    extendy: f32,
     glow: GlowType,
+    /// 参照するスプライトシートの番号(mamodelのid列、Modification::Id)
+    sheet: i32,
     // 水平方向の反転
     horizontal_flip: bool,
     // 鉛直方向の反転
     vertical_flip: bool,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum UnitForm {
     Form1,
     Form2,
     Form3,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum UnitSelector {
     Unit((u16, UnitForm)),
     Enemy(u16),
 }
 
 impl UnitForm {
+    pub const ALL: [Self; 3] = [Self::Form1, Self::Form2, Self::Form3];
+
     pub fn to_char(self) -> char {
         match self {
             Self::Form1 => 'f',
@@ -132,7 +186,7 @@ impl UnitForm {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum AnimSelector {
     Walk,
     Idle,
@@ -143,6 +197,38 @@ pub enum AnimSelector {
     BurrowUp,
 }
 
+impl AnimSelector {
+    pub const ALL: [Self; 7] = [
+        Self::Walk,
+        Self::Idle,
+        Self::Attack,
+        Self::HitBack,
+        Self::BurrowDown,
+        Self::BurrowMove,
+        Self::BurrowUp,
+    ];
+
+    /// コマンドラインやファイル名で使う名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Walk => "walk",
+            Self::Idle => "idle",
+            Self::Attack => "attack",
+            Self::HitBack => "hitback",
+            Self::BurrowDown => "burrowdown",
+            Self::BurrowMove => "burrowmove",
+            Self::BurrowUp => "burrowup",
+        }
+    }
+
+    /// 大文字小文字は区別しない
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|anim| anim.name().eq_ignore_ascii_case(name))
+    }
+}
+
 impl UnitSelector {
     pub fn unit_type(&self) -> &'static str {
         match self {
@@ -208,6 +294,26 @@ impl UnitSelector {
         self.filename() + ".png.size"
     }
 
+    /// k番目のスプライトシートの拡張子を除いたパス(0ならfilenameと同じ)
+    pub fn sheet_filename(&self, k: usize) -> String {
+        match k {
+            0 => self.filename(),
+            k => format!("{}_{k:02}", self.filename()),
+        }
+    }
+
+    pub fn sheet_image(&self, k: usize) -> String {
+        self.sheet_filename(k) + ".png"
+    }
+
+    pub fn sheet_imgcut(&self, k: usize) -> String {
+        self.sheet_filename(k) + ".imgcut"
+    }
+
+    pub fn sheet_image_size(&self, k: usize) -> String {
+        self.sheet_filename(k) + ".png.size"
+    }
+
     pub fn maanim(&self, selector: AnimSelector) -> String {
         self.filename()
             + match selector {
@@ -221,23 +327,51 @@ impl UnitSelector {
             }
     }
 
-    pub fn load_imgcut(&self) -> Result<Vec<Imgcut>, Error> {
-        Imgcut::load(Path::new(BC_ASSET_PATH).join(self.imgcuts())).map(|(_, v)| v)
+    pub fn load_imgcut(&self, source: &dyn AssetSource) -> Result<Vec<Imgcut>, Error> {
+        Imgcut::load(source, self.imgcuts()).map(|(_, v)| v)
+    }
+
+    pub fn load_mamodel(&self, source: &dyn AssetSource) -> Result<Mamodels, Error> {
+        Mamodels::load(source, self.mamodels())
+    }
+
+    pub fn load_maanim(
+        &self,
+        source: &dyn AssetSource,
+        selector: AnimSelector,
+    ) -> Result<Maanim, Error> {
+        Maanim::load(source, self.maanim(selector))
+    }
+
+    /// AssetServer経由で非同期に読み込む
+    /// AssetServerは[`BcAssetSource::asset_server`]で作ったものを使う
+    pub fn imgcut_handle(&self, asset_server: &AssetServer) -> Handle<ImgcutSheet> {
+        asset_server.load(Path::new(MOUNT).join(self.imgcuts()))
+    }
+
+    pub fn mamodel_handle(&self, asset_server: &AssetServer) -> Handle<Mamodels> {
+        asset_server.load(Path::new(MOUNT).join(self.mamodels()))
     }
 
-    pub fn load_mamodel(&self) -> Result<Mamodels, Error> {
-        Mamodels::load(Path::new(BC_ASSET_PATH).join(self.mamodels()))
+    pub fn maanim_handle(&self, asset_server: &AssetServer, selector: AnimSelector) -> Handle<Maanim> {
+        asset_server.load(Path::new(MOUNT).join(self.maanim(selector)))
     }
 
-    pub fn load_maanim(&self, selector: AnimSelector) -> Result<Maanim, Error> {
-        Maanim::load(Path::new(BC_ASSET_PATH).join(self.maanim(selector)))
+    pub fn image_handle(&self, asset_server: &AssetServer) -> Handle<Image> {
+        asset_server.load(Path::new(MOUNT).join(self.image()))
+    }
+
+    pub fn sheet_image_handle(&self, asset_server: &AssetServer, k: usize) -> Handle<Image> {
+        asset_server.load(Path::new(MOUNT).join(self.sheet_image(k)))
     }
 }
 
-use super::{ASSET_PATH, BC_ASSET_PATH};
+use super::catalog::{UnitCatalog, INDEX_PATH};
+use super::source::{AssetSource, BcAssetSource, MOUNT};
 impl UnitImages {
     fn load(
         id_set: &[UnitSelector],
+        source: &dyn AssetSource,
         asset_server: &Res<AssetServer>,
         meshes: &mut ResMut<Assets<Mesh>>,
         color_materials: &mut ResMut<Assets<ColorMaterial>>,
@@ -249,6 +383,7 @@ impl UnitImages {
                 .map(|id| {
                     match UnitImage::load(
                         *id,
+                        source,
                         asset_server,
                         meshes,
                         color_materials,
@@ -291,47 +426,183 @@ impl UnitImages {
 //         .collect()
 // }
 
-/// ファイルに書き込まれている数字: width + height << 32
-fn load_image_size(selector: &UnitSelector) -> Result<(u32, u32), super::error::Error> {
-    let s = std::fs::read_to_string(
-        Path::new(ASSET_PATH)
-            .join(BC_ASSET_PATH)
-            .join(selector.image_size()),
-    )?;
-    let num: u64 = s
-        .parse()
-        .map_err(|e| super::error::Error::new(super::error::ErrorKind::FileFormatError, e))?;
+/// PNGのIHDRチャンクから(width, height)を読む
+fn png_size(header: &[u8]) -> Option<(u32, u32)> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if header.len() < 24 || !header.starts_with(SIGNATURE) || &header[12..16] != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(header[16..20].try_into().unwrap());
+    let height = u32::from_be_bytes(header[20..24].try_into().unwrap());
+    Some((width, height))
+}
+
+/// 画像の大きさをPNGのヘッダから読む
+/// 読めなかった場合は<name>.png.sizeファイル(width + height << 32)を使う
+fn load_image_size(
+    source: &dyn AssetSource,
+    selector: &UnitSelector,
+    sheet: usize,
+) -> Result<(u32, u32), super::error::Error> {
+    let from_png = source
+        .read(Path::new(&selector.sheet_image(sheet)))
+        .ok()
+        .and_then(|png| png_size(&png));
+    if let Some(size) = from_png {
+        return Ok(size);
+    }
+
+    let size_path = selector.sheet_image_size(sheet);
+    let s = source.read(Path::new(&size_path))?;
+    let num: u64 = String::from_utf8_lossy(&s).trim().parse().map_err(|e| {
+        super::error::Error::new(super::error::ErrorKind::FileFormatError, e).with_path(&size_path)
+    })?;
     Ok((num as u32, (num >> 32) as u32))
 }
 
+/// 全imgcutを含む大きさ
+/// 画像の大きさが分からないときに仮の値として使う
+fn imgcut_extent(imgcuts: &[Imgcut]) -> (u32, u32) {
+    imgcuts.iter().fold((1, 1), |(w, h), imgcut| {
+        (w.max(imgcut.x + imgcut.width), h.max(imgcut.y + imgcut.height))
+    })
+}
+
+impl UnitSheet {
+    fn load(
+        selector: UnitSelector,
+        k: usize,
+        imgcuts: Vec<Imgcut>,
+        source: &dyn AssetSource,
+        asset_server: &Res<AssetServer>,
+        meshes: &mut ResMut<Assets<Mesh>>,
+    ) -> Self {
+        let (w, h) = load_image_size(source, &selector, k).unwrap_or_else(|err| {
+            println!("{selector:?}: {err}\n画像の読み込み後に大きさを合わせる");
+            imgcut_extent(&imgcuts)
+        });
+        Self {
+            meshes: imgcuts
+                .iter()
+                .map(|imgcut| meshes.add(imgcut.mesh(w, h)).into())
+                .collect(),
+            size: imgcuts.iter().cloned().map(Size2d::from).collect(),
+            imgcuts,
+            texture: selector.sheet_image_handle(asset_server, k),
+            texture_size: Size2d {
+                width: w,
+                height: h,
+            },
+        }
+    }
+}
+
+/// id列やModification::Idの値からシートの番号を求める(負の値は0)
+fn sheet_index(id: i32) -> usize {
+    id.max(0) as usize
+}
+
 impl UnitImage {
     fn load(
         selector: UnitSelector,
+        source: &dyn AssetSource,
         asset_server: &Res<AssetServer>,
         meshes: &mut ResMut<Assets<Mesh>>,
         color_materials: &mut ResMut<Assets<ColorMaterial>>,
         glow_materials: &mut ResMut<Assets<Glow1Material>>,
     ) -> Result<Self, super::error::Error> {
-        let models = Mamodels::load(Path::new(BC_ASSET_PATH).join(selector.mamodels()))?;
-        let imgcuts = Imgcut::load(Path::new(BC_ASSET_PATH).join(selector.imgcuts()))?.1;
-        let (w, h) = load_image_size(&selector)?;
-        let meshes = imgcuts
-            .iter()
-            .map(|imgcut| meshes.add(imgcut.mesh(w, h)).into())
-            .collect();
-        let texture: Handle<Image> =
-            asset_server.load(Path::new(BC_ASSET_PATH).join(selector.image()));
+        let models = selector.load_mamodel(source)?;
+        let imgcuts = selector.load_imgcut(source)?;
+        for warning in validate::check(&models, &imgcuts, &[])? {
+            println!("{selector:?}: {warning}");
+        }
+        let mut sheets = vec![UnitSheet::load(selector, 0, imgcuts, source, asset_server, meshes)];
+        // 2枚目以降は番号が続く限り読む
+        for k in 1.. {
+            let path = selector.sheet_imgcut(k);
+            if !source.is_file(Path::new(&path)) {
+                break;
+            }
+            match Imgcut::load(source, &path) {
+                Ok((_, imgcuts)) => {
+                    sheets.push(UnitSheet::load(selector, k, imgcuts, source, asset_server, meshes));
+                }
+                Err(err) => {
+                    println!("{selector:?}: {err}");
+                    break;
+                }
+            }
+        }
         Ok(Self {
             materials: models
                 .models
                 .iter()
-                .map(|model| model.get_material(&texture, color_materials, glow_materials))
+                .map(|model| {
+                    let sheet = sheets.get(sheet_index(model.id)).unwrap_or(&sheets[0]);
+                    model.get_material(&sheet.texture, color_materials, glow_materials)
+                })
                 .collect(),
-            size: imgcuts.into_iter().map(Size2d::from).collect(),
-            meshes,
+            sheets,
             mamodels: models,
         })
     }
+
+    /// 無いシートは0番を使う
+    pub fn sheet(&self, id: i32) -> &UnitSheet {
+        self.sheets.get(sheet_index(id)).unwrap_or(&self.sheets[0])
+    }
+
+    /// 無い画像は大きさ0
+    pub fn size(&self, sheet: i32, img: i32) -> Size2d {
+        self.sheet(sheet)
+            .size
+            .get(img as usize)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn mesh(&self, sheet: i32, img: i32) -> Mesh2dHandle {
+        self.sheet(sheet)
+            .meshes
+            .get(img as usize)
+            .cloned()
+            .unwrap_or_default()
+    }
+}
+
+/// 画像が読み込まれたら実際の大きさでmeshのUVを作り直す
+fn update_image_size(
+    mut events: EventReader<AssetEvent<Image>>,
+    images: Res<Assets<Image>>,
+    mut unit_images: ResMut<UnitImages>,
+    mut meshes: ResMut<Assets<Mesh>>,
+) {
+    for event in events.iter() {
+        let (AssetEvent::Created { handle } | AssetEvent::Modified { handle }) = event else {
+            continue;
+        };
+        let Some(image) = images.get(handle) else {
+            continue;
+        };
+        let size = Size2d {
+            width: image.size().x as u32,
+            height: image.size().y as u32,
+        };
+        for sheet in unit_images
+            .images
+            .iter_mut()
+            .flatten()
+            .flat_map(|unit_image| &mut unit_image.sheets)
+            .filter(|sheet| sheet.texture == *handle && sheet.texture_size != size)
+        {
+            for (imgcut, mesh) in sheet.imgcuts.iter().zip(&sheet.meshes) {
+                if let Some(mesh) = meshes.get_mut(&mesh.0) {
+                    *mesh = imgcut.mesh(size.width, size.height);
+                }
+            }
+            sheet.texture_size = size;
+        }
+    }
 }
 
 fn space_pressed(input: Res<Input<KeyCode>>) -> bool {
@@ -344,15 +615,16 @@ impl State {
             parent: model.parent,
             img: model.imgind,
             zorder: model.zorder,
-            x: model.posx,
-            y: model.posy,
-            pivotx: model.pivotx,
-            pivoty: model.pivoty,
-            scalex: model.scalex,
-            scaley: model.scaley,
-            angle: model.angle,
-            opacity: model.opacity,
+            x: model.posx as f32,
+            y: model.posy as f32,
+            pivotx: model.pivotx as f32,
+            pivoty: model.pivoty as f32,
+            scalex: model.scalex as f32,
+            scaley: model.scaley as f32,
+            angle: model.angle as f32,
+            opacity: model.opacity as f32,
             glow: model.glow,
+            sheet: model.id,
             ..default()
         }
     }
@@ -360,36 +632,92 @@ impl State {
     pub fn load_diff(&mut self, diff: StateDiffVal) {
         match diff {
             StateDiffVal::Parent(v) => self.parent = v,
+            StateDiffVal::Id(v) => self.sheet = v,
             StateDiffVal::Sprite(v) => self.img = v,
             StateDiffVal::Zorder(v) => self.zorder = v,
-            StateDiffVal::Posx(v) => self.x = v,
-            StateDiffVal::Posy(v) => self.y = v,
-            StateDiffVal::Pivotx(v) => self.pivotx = v,
-            StateDiffVal::Pivoty(v) => self.pivoty = v,
-            StateDiffVal::Scale(v) => self.scale = v,
-            StateDiffVal::Scalex(v) => self.scalex = v,
-            StateDiffVal::Scaley(v) => self.scaley = v,
-            StateDiffVal::Angle(v) => self.angle = v,
-            StateDiffVal::Opacity(v) => self.opacity = v,
+            StateDiffVal::Posx(v) => self.x = v as f32,
+            StateDiffVal::Posy(v) => self.y = v as f32,
+            StateDiffVal::Pivotx(v) => self.pivotx = v as f32,
+            StateDiffVal::Pivoty(v) => self.pivoty = v as f32,
+            StateDiffVal::Scale(v) => self.scale = v as f32,
+            StateDiffVal::Scalex(v) => self.scalex = v as f32,
+            StateDiffVal::Scaley(v) => self.scaley = v as f32,
+            StateDiffVal::Angle(v) => self.angle = v as f32,
+            StateDiffVal::Opacity(v) => self.opacity = v as f32,
             StateDiffVal::HorizontalFlip(v) => self.horizontal_flip = v,
             StateDiffVal::VerticalFlip(v) => self.vertical_flip = v,
+            StateDiffVal::ExtendX(v) => self.extendx = v as f32,
+            StateDiffVal::ExtendY(v) => self.extendy = v as f32,
             _ => (),
         };
     }
+
+    /// 途中のフレームの値を読み込む
+    /// 補間しない値(親、画像、zorder、反転)はフレームの値をそのまま渡す
+    pub fn load_value(&mut self, modification: Modification, value: f32) {
+        match modification {
+            Modification::Xpos => self.x = value,
+            Modification::Ypos => self.y = value,
+            Modification::Pivotx => self.pivotx = value,
+            Modification::Pivoty => self.pivoty = value,
+            Modification::Scale => self.scale = value,
+            Modification::Scalex => self.scalex = value,
+            Modification::Scaley => self.scaley = value,
+            Modification::Angle => self.angle = value,
+            Modification::Opacity => self.opacity = value,
+            Modification::ExtendX => self.extendx = value,
+            Modification::ExtendY => self.extendy = value,
+            _ => self.load_diff(StateDiffVal::new(modification, value as i32)),
+        }
+    }
+
+    /// self(t = 0)とother(t = 1)の間の状態
+    /// 補間できない値(親、画像、zorder、発光、反転)はt = 0.5で切り替える
+    pub fn blend(&self, other: &State, t: f32) -> State {
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        let discrete = if t < 0.5 { self } else { other };
+        State {
+            x: lerp(self.x, other.x),
+            y: lerp(self.y, other.y),
+            pivotx: lerp(self.pivotx, other.pivotx),
+            pivoty: lerp(self.pivoty, other.pivoty),
+            scale: lerp(self.scale, other.scale),
+            scalex: lerp(self.scalex, other.scalex),
+            scaley: lerp(self.scaley, other.scaley),
+            angle: lerp(self.angle, other.angle),
+            opacity: lerp(self.opacity, other.opacity),
+            extendx: lerp(self.extendx, other.extendx),
+            extendy: lerp(self.extendy, other.extendy),
+            ..discrete.clone()
+        }
+    }
 }
 
+#[allow(clippy::too_many_arguments)]
 fn startup_sprite_images(
     mut commands: Commands,
+    source: Res<BcAssetSource>,
+    cache: Res<TrackCache>,
+    renderer: Res<UnitRenderer>,
     asset_server: Res<AssetServer>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut color_materials: ResMut<Assets<ColorMaterial>>,
     mut glow_materials: ResMut<Assets<Glow1Material>>,
 ) {
     let unit_id = std::fs::read_to_string("num.txt").unwrap().parse().unwrap();
-    let selector = UnitSelector::Unit((unit_id, UnitForm::Form2));
+    let catalog = UnitCatalog::load_or_scan(&**source, INDEX_PATH);
+    // 第2形態が無ければある形態を使う
+    let form = catalog
+        .forms(unit_id)
+        .into_iter()
+        .max_by_key(|&form| form == UnitForm::Form2)
+        .unwrap_or(UnitForm::Form2);
+    let selector = UnitSelector::Unit((unit_id, form));
+    commands.insert_resource(catalog);
     // 画像関連のデータ
     let image_data = UnitImages::load(
         &[selector],
+        &**source,
         &asset_server,
         &mut meshes,
         &mut color_materials,
@@ -398,26 +726,26 @@ fn startup_sprite_images(
 
     // println!("loaded: {mamodels:?}");
 
-    // アニメーションロード
-    let maanim =
-        Maanim::load(Path::new(BC_ASSET_PATH).join(selector.maanim(AnimSelector::Attack))).ok();
+    // アニメーションロード(計算済みのキャッシュがあればそれを使う)
+    let tracks = cache.load(&**source, selector).unwrap_or_else(|err| {
+        println!("loading animation failed (unit id: {selector:?})\nerror info: {err:#?}");
+        Default::default()
+    });
 
+    let unit_image = image_data.images[0].as_ref().unwrap();
     let UnitImage {
         materials: material_handles,
-        meshes: mesh_handles,
-        size: sizes,
         mamodels,
-    } = image_data.images[0].as_ref().unwrap();
+        ..
+    } = unit_image;
 
     // for (i, model) in mamodels.models.iter().enumerate() {
     //     println!("{i}: {model:?}");
     // }
     // アニメーション定義
-    commands.insert_resource(
-        maanim
-            .map(|anim| StateGenerator::from_anim(anim ,mamodels))
-            .unwrap_or_else(|| StateGenerator::empty(mamodels)),
-    );
+    let mut machine = AnimStateMachine::new(tracks, mamodels.clone()).with_transition(0.15);
+    let mut generator = machine.generator();
+    machine.start(AnimSelector::Walk, &mut generator);
 
     // commands.insert_resource(StateGenerator::with_raw_model(&mamodels));
 
@@ -425,12 +753,19 @@ fn startup_sprite_images(
     let parent = commands
         .spawn((
             Unit,
+            machine,
+            generator,
             SpatialBundle {
                 transform: Transform::from_xyz(0., -300., 0.),
                 ..default()
             },
         ))
         .id();
+    if *renderer == UnitRenderer::Batched {
+        UnitBatch::spawn(&mut commands, parent, 0);
+        commands.insert_resource(image_data);
+        return;
+    }
     let ids = UnitSpriteId {
         parts: material_handles
             .iter()
@@ -442,34 +777,14 @@ fn startup_sprite_images(
                     .set_parent(parent)
                     .id();
 
-                let size = sizes.get(model.imgind as usize).copied().unwrap_or(Size2d {
-                    width: 0,
-                    height: 0,
-                });
-                // let size =
-                // if [0, 2, 11, 62].contains(&i) {
-                //     sizes.get(model.imgind as usize).copied().unwrap_or(Size2d {
-                //         width: 0,
-                //         height: 0,
-                //     })
-                // } else {
-                //     Size2d::default()
-                // };
-                let mesh_temp = Mesh2dHandle::default();
-                let mesh = mesh_handles
-                    .get(model.imgind as usize)
-                    .unwrap_or(&mesh_temp);
+                // TransformはそのままでGlobalTransformをupdate_textureで書く
+                let mesh = unit_image.mesh(model.id, model.imgind);
                 let child = match mate {
                     NormalMaterial(m) => commands.spawn((
                         UnitSpritePartChild,
                         MaterialMesh2dBundle {
                             mesh: mesh.clone(),
                             material: m.clone(),
-                            transform: Transform::from_scale(Vec3::new(
-                                size.width as f32,
-                                size.height as f32,
-                                1.,
-                            )),
                             ..default()
                         },
                     )),
@@ -478,11 +793,6 @@ fn startup_sprite_images(
                         MaterialMesh2dBundle {
                             mesh: mesh.clone(),
                             material: m.clone(),
-                            transform: Transform::from_scale(Vec3::new(
-                                size.width as f32,
-                                size.height as f32,
-                                1.,
-                            )),
                             ..default()
                         },
                     )),
@@ -499,32 +809,32 @@ fn startup_sprite_images(
 
 #[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn update_unit_sprite(
-    mut commands: Commands,
     mut query_parent: Query<
-        &mut Transform,
-        (With<UnitSpritePartParent>, Without<UnitSpritePartChild>),
+        &mut GlobalTransform,
+        (With<UnitSpritePartParent>, Without<UnitSpritePartChild>, Without<Unit>),
     >,
     mut query_child: Query<
         (
-            &mut Transform,
+            &mut GlobalTransform,
             &mut Mesh2dHandle,
             Option<&Handle<ColorMaterial>>,
             Option<&Handle<Glow1Material>>,
         ),
-        (With<UnitSpritePartChild>, Without<UnitSpritePartParent>),
+        (With<UnitSpritePartChild>, Without<UnitSpritePartParent>, Without<Unit>),
     >,
-    mut states: ResMut<StateGenerator>,
+    units: Query<(&StateGenerator, &GlobalTransform), With<Unit>>,
     image_data: Res<UnitImages>,
     ids: Res<UnitSpriteId>,
     mut color_materials: ResMut<Assets<ColorMaterial>>,
     mut glow_materials: ResMut<Assets<Glow1Material>>,
     // input: Res<Input<KeyCode>>,
 ) {
+    let (generator, unit) = units.single();
     update_texture(
-        &mut commands,
         &mut query_parent,
         &mut query_child,
-        states.next_state(),
+        unit,
+        &generator.current_state(),
         image_data.images[0].as_ref().unwrap(),
         ids.as_ref(),
         &mut color_materials,
@@ -549,7 +859,8 @@ fn debug_system(
     input: Res<Input<KeyCode>>,
     mut commands: Commands,
 ) {
-    if input.just_pressed(KeyCode::Space) {
+    // Spaceは再生/一時停止に使うのでF1
+    if input.just_pressed(KeyCode::F1) {
         // let (t11, t62) = (
         //     query.get(ids.parts[11].parent).unwrap(),
         //     query.get(ids.parts[62].parent).unwrap(),
@@ -578,6 +889,69 @@ fn debug_system(
     // }
 }
 
+/// 再生中のアニメーションを進める(描画はこの後)
+fn advance_generators(mut generators: Query<&mut StateGenerator>, time: Res<Time>) {
+    for mut generator in &mut generators {
+        generator.advance(time.delta_seconds());
+    }
+}
+
+/// Space: 再生/一時停止、./,: 1フレーム進める/戻す、R: 逆再生、
+/// Up/Down: 再生速度を2倍/半分、Home: 先頭に戻す
+fn playback_control(mut generators: Query<&mut StateGenerator>, input: Res<Input<KeyCode>>) {
+    for mut generator in &mut generators {
+        control_generator(&mut generator, &input);
+    }
+}
+
+fn control_generator(generator: &mut StateGenerator, input: &Input<KeyCode>) {
+    if input.just_pressed(KeyCode::Space) {
+        generator.toggle_pause();
+    }
+    if input.just_pressed(KeyCode::Period) {
+        generator.step_forward();
+    }
+    if input.just_pressed(KeyCode::Comma) {
+        generator.step_backward();
+    }
+    if input.just_pressed(KeyCode::R) {
+        let reverse = !generator.is_reverse();
+        generator.set_reverse(reverse);
+    }
+    if input.just_pressed(KeyCode::Up) {
+        let speed = generator.speed() * 2.;
+        generator.set_speed(speed);
+    }
+    if input.just_pressed(KeyCode::Down) {
+        let speed = generator.speed() / 2.;
+        generator.set_speed(speed);
+    }
+    if input.just_pressed(KeyCode::Home) {
+        generator.seek(0.);
+    }
+}
+
+/// W: Walk、I: Idle、A: Attack、H: HitBack、B: BurrowDown、U: BurrowUp
+fn request_control(
+    units: Query<Entity, With<AnimStateMachine>>,
+    input: Res<Input<KeyCode>>,
+    mut requests: EventWriter<AnimRequest>,
+) {
+    let anim = [
+        (KeyCode::W, AnimSelector::Walk),
+        (KeyCode::I, AnimSelector::Idle),
+        (KeyCode::A, AnimSelector::Attack),
+        (KeyCode::H, AnimSelector::HitBack),
+        (KeyCode::B, AnimSelector::BurrowDown),
+        (KeyCode::U, AnimSelector::BurrowUp),
+    ]
+    .into_iter()
+    .find(|&(key, _)| input.just_pressed(key));
+    if let Some((_, anim)) = anim {
+        requests.send_batch(units.iter().map(|entity| AnimRequest { entity, anim }));
+    }
+}
+
 fn debug_system2(
     mut query: Query<&mut Transform, With<UnitSpritePartParent>>,
     ids: Res<UnitSpriteId>,
@@ -625,10 +999,29 @@ pub struct PluginTemp;
 
 impl Plugin for PluginTemp {
     fn build(&self, app: &mut App) {
-        let timer = on_timer(Duration::from_secs_f32(1. / 30.));
-        app.add_startup_system(startup_sprite_images)
-            .add_system(update_unit_sprite.run_if(timer))
-            .add_system(debug_system);
+        app.init_resource::<BcAssetSource>()
+            .init_resource::<TrackCache>()
+            .init_resource::<UnitRenderer>()
+            .add_startup_system(startup_sprite_images)
+            .add_event::<AnimRequest>()
+            .add_event::<AnimationStarted>()
+            .add_event::<AnimationLooped>()
+            .add_event::<AnimationFinished>()
+            .add_event::<FrameMarker>()
+            .add_system(playback_control.before(advance_generators))
+            .add_system(request_control.before(update_state_machine))
+            .add_system(advance_generators)
+            .add_system(update_state_machine.after(advance_generators))
+            .add_system(
+                update_unit_sprite
+                    .in_base_set(CoreSet::PostUpdate)
+                    .after(TransformSystem::TransformPropagate)
+                    .before(VisibilitySystems::CheckVisibility)
+                    .run_if(resource_exists::<UnitSpriteId>()),
+            )
+            .add_system(update_unit_batch.after(update_state_machine))
+            .add_system(update_image_size)
+            .add_system(debug_system.run_if(resource_exists::<UnitSpriteId>()));
     }
 }
 pub struct UnitSpriteIter<'a> {
@@ -670,15 +1063,17 @@ impl UnitState {
                 .models
                 .iter()
                 .map(|model| State {
-                    x: 0,
-                    y: 0,
-                    pivotx: 0,
-                    pivoty: 0,
+                    x: 0.,
+                    y: 0.,
+                    pivotx: 0.,
+                    pivoty: 0.,
                     scale: models.scale_ratio as _,
                     scalex: models.scale_ratio as _,
                     scaley: models.scale_ratio as _,
-                    angle: 0,
+                    angle: 0.,
                     opacity: models.opacity_ratio as _,
+                    extendx: models.scale_ratio as _,
+                    extendy: models.scale_ratio as _,
                     ..State::from_model(model)
                 })
                 .collect(),
@@ -690,174 +1085,81 @@ impl UnitState {
             .iter_mut()
             .zip(&models.models)
             .for_each(|(state, model)| {
-                state.x += model.posx;
-                state.y += model.posy;
-                state.pivotx += model.pivotx;
-                state.pivoty += model.pivoty;
-                state.scalex *= model.scalex;
-                state.scaley *= model.scaley;
-                state.angle += model.angle;
-                state.opacity *= model.opacity;
+                state.x += model.posx as f32;
+                state.y += model.posy as f32;
+                state.pivotx += model.pivotx as f32;
+                state.pivoty += model.pivoty as f32;
+                state.scalex *= model.scalex as f32;
+                state.scaley *= model.scaley as f32;
+                state.angle += model.angle as f32;
+                state.opacity *= model.opacity as f32;
             });
     }
+
+    /// パーツごとにState::blendする(パーツ数は同じであること)
+    pub fn blend(&self, other: &UnitState, t: f32) -> UnitState {
+        UnitState {
+            states: self
+                .states
+                .iter()
+                .zip(&other.states)
+                .map(|(a, b)| a.blend(b, t))
+                .collect(),
+        }
+    }
 }
 
+/// solveの結果を各パーツのGlobalTransformに直接書く
+/// 親子関係は作ったときのままで付け替えないので、TransformSystem::TransformPropagateの後に呼ぶ
 #[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn update_texture(
-    commands: &mut Commands,
     query_parent: &mut Query<
-        &mut Transform,
-        (With<UnitSpritePartParent>, Without<UnitSpritePartChild>),
+        &mut GlobalTransform,
+        (With<UnitSpritePartParent>, Without<UnitSpritePartChild>, Without<Unit>),
     >,
     query_child: &mut Query<
         (
-            &mut Transform,
+            &mut GlobalTransform,
             &mut Mesh2dHandle,
             Option<&Handle<ColorMaterial>>,
             Option<&Handle<Glow1Material>>,
         ),
-        (With<UnitSpritePartChild>, Without<UnitSpritePartParent>),
+        (With<UnitSpritePartChild>, Without<UnitSpritePartParent>, Without<Unit>),
     >,
-    mut states: UnitState,
+    unit: &GlobalTransform,
+    states: &UnitState,
     image_data: &UnitImage,
     ids: &UnitSpriteId,
     color_materials: &mut ResMut<Assets<ColorMaterial>>,
     glow_materials: &mut ResMut<Assets<Glow1Material>>,
 ) {
-    states.apply_model(&image_data.mamodels);
-    let mut opacities: Vec<Option<(f32, bool)>> = vec![None; states.states.len()];
-    for (state, id) in states.states.iter().zip(&ids.parts).skip(1) {
-        commands.entity(id.parent).remove_parent();
-    }
-    // println!("state6: {:?}", states.states[6]);
-    for (i, (state, id)) in states.states.iter().zip(&ids.parts).enumerate() {
-        let opacity;
-        let zorder;
-        let mesh;
-
-        let opacity_ratio = (image_data.mamodels.opacity_ratio as f32).powi(2);
-        let scale_ratio = (image_data.mamodels.scale_ratio as f32).powi(3);
-        let angle_ratio = image_data.mamodels.angle_ratio as f32;
-        let mut angle_direction = 1.;
-        if i > 0 {
-            commands
-                .entity(id.parent)
-                .set_parent(ids.parts[state.parent as usize].parent);
-            mesh = match image_data.meshes.get(state.img as usize) {
-                Some(m) => m.clone(),
-                None => Mesh2dHandle::default(),
-            };
-            zorder = state.zorder - states.states[state.parent as usize].zorder;
-        } else {
-            mesh = Mesh2dHandle::default();
-            zorder = state.zorder;
-        }
-
-        if state.parent < 0 {
-            opacity = state.opacity as f32 / opacity_ratio;
-            opacities[i] = Some((
-                opacity,
-                state.scalex.is_positive() ^ state.scaley.is_positive(),
-            ));
-        } else if let Some((opa, sig)) = opacities[state.parent as usize] {
-            opacity = opa * state.opacity as f32 / opacity_ratio;
-            if sig {
-                angle_direction = -angle_direction;
-            }
-            opacities[i] = Some((
-                opacity,
-                state.scalex.is_positive()
-                    ^ state.scaley.is_positive()
-                    // ^ state.vertical_flip
-                    // ^ state.horizontal_flip
-                    ^ sig,
-            ));
-        } else {
-            let mut parent_ind = state.parent as usize;
-            let mut indice = vec![i];
-            while opacities[parent_ind].is_none() {
-                indice.push(parent_ind);
-                parent_ind = states.states[parent_ind].parent as usize;
-            }
-            let mut prev_ind = parent_ind;
-            for i in indice.iter().rev().copied() {
-                opacities[i] = opacities[prev_ind].map(|(opa, sig)| {
-                    let state = &states.states[i];
-                    (
-                        opa * states.states[i].opacity as f32 / opacity_ratio,
-                        state.scalex.is_positive()
-                            ^ state.scaley.is_positive()
-                            // ^ state.vertical_flip
-                            // ^ state.horizontal_flip
-                            ^ sig,
-                    )
-                });
-                prev_ind = i;
-            }
-            opacity = opacities[i].unwrap().0;
-            if opacities[state.parent as usize].unwrap().1 {
-                angle_direction = -angle_direction;
-            }
-        }
-
-        let size = image_data
-            .size
-            .get(state.img as usize)
-            .copied()
-            .unwrap_or_default();
-        let child_translation = Vec3::new(
-            size.width as f32 / 2. - state.pivotx as f32,
-            state.pivoty as f32 - size.height as f32 / 2.,
-            0.,
-        );
-
-        let scalex = if state.horizontal_flip {
-            -state.scalex as f32
-        } else {
-            state.scalex as f32
-        } * state.scale as f32
-            / scale_ratio;
-        let scaley = if state.vertical_flip {
-            -state.scaley as f32
-        } else {
-            state.scaley as f32
-        } * state.scale as f32
-            / scale_ratio;
-        
-        let mut angle = -state.angle as f32 / angle_ratio * 2. * std::f32::consts::PI;
-        if state.horizontal_flip {
-            angle = -angle;
-        }
-        if state.vertical_flip {
-            angle = -angle;
-        }
-        let parent_transform = Transform::from_xyz(state.x as f32, -state.y as f32, zorder as f32 + i as f32 / states.states.len() as f32)
-            .with_rotation(Quat::from_rotation_z(angle * angle_direction))
-            .with_scale(Vec3::new(scalex, scaley, 1.));
-
-        *query_parent.get_mut(id.parent).unwrap() = parent_transform;
+    let unit = unit.affine();
+    for (part, id) in solve(states, image_data).into_iter().zip(&ids.parts) {
+        *query_parent.get_mut(id.parent).unwrap() = (unit * part.transform).into();
 
         let (mut transform, mut mesh_handle, mate1, mate2) = query_child.get_mut(id.child).unwrap();
+        *transform = (unit * part.image).into();
+        let sheet = &image_data.sheets[part.sheet];
+        *mesh_handle = match part.img {
+            Some(img) => sheet.meshes[img].clone(),
+            None => Mesh2dHandle::default(),
+        };
 
-        transform.translation = child_translation;
-        *mesh_handle = mesh;
-
+        let texture = &sheet.texture;
         if let Some(material) = mate1 {
-            color_materials
-                .get_mut(material)
-                .unwrap()
-                .color
-                .set_a(opacity);
+            let material = color_materials.get_mut(material).unwrap();
+            material.color.set_a(part.opacity);
+            if material.texture.as_ref() != Some(texture) {
+                material.texture = Some(texture.clone());
+            }
         } else if let Some(material) = mate2 {
-            glow_materials
-                .get_mut(material)
-                .unwrap()
-                .color
-                .set_a(opacity);
+            let material = glow_materials.get_mut(material).unwrap();
+            material.color.set_a(part.opacity);
+            if material.texture.as_ref() != Some(texture) {
+                material.texture = Some(texture.clone());
+            }
         }
-        // println!("update");
     }
-    // println!("opacities: {opacities:#?}");
 }
 
 // impl<'a> Iterator for UnitSpriteIter<'a> {
@@ -952,3 +1254,115 @@ pub struct ModelMeta {
 impl Plugin for BcuAnim {
     fn build(&self, app: &mut App) {}
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn blend() {
+        let a = State {
+            img: 1,
+            zorder: 2,
+            x: 10.,
+            angle: 0.,
+            opacity: 1000.,
+            ..default()
+        };
+        let b = State {
+            img: 5,
+            zorder: 0,
+            x: 30.,
+            angle: 900.,
+            opacity: 0.,
+            horizontal_flip: true,
+            ..default()
+        };
+        let quarter = a.blend(&b, 0.25);
+        assert_eq!((quarter.x, quarter.angle, quarter.opacity), (15., 225., 750.));
+        assert_eq!((quarter.img, quarter.zorder, quarter.horizontal_flip), (1, 2, false));
+        let half = a.blend(&b, 0.5);
+        assert_eq!((half.img, half.zorder, half.horizontal_flip), (5, 0, true));
+        assert_eq!(a.blend(&b, 1.).x, b.x);
+
+        let states = UnitState {
+            states: vec![a.clone(), b.clone()],
+        };
+        let swapped = UnitState { states: vec![b, a] };
+        let mid = states.blend(&swapped, 0.5);
+        assert_eq!(mid.states[0].x, 20.);
+        assert_eq!(mid.states[1].img, 1);
+    }
+
+    #[test]
+    fn extend() {
+        let models = Mamodels::from_reader(
+            &b"[modelanim:model2]\n1\n2\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,1,0,0,0,0,1000,1000,0,1000,0\n\
+            1000,3600,1000\n"[..],
+        )
+        .unwrap();
+        let maanim = Maanim::from_reader(
+            &b"[modelanim:animation2]\n1\n2\n\
+            0,50,-1,0,0\n2\n0,1000,0,0\n4,3000,0,0\n\
+            0,52,-1,0,0\n1\n0,500,0,0\n"[..],
+        )
+        .unwrap();
+        let generator = StateGenerator::from_anim(maanim, &models);
+        let state = generator.state_at(2.);
+        let (root, child) = (&state.states[0], &state.states[1]);
+        assert_eq!((root.extendx, root.extendy), (2000., 500.));
+        // scalex/scaleyとは別で、子パーツには伝わらない
+        assert_eq!((root.scalex, root.scaley), (1000., 1000.));
+        assert_eq!((child.extendx, child.extendy), (1000., 1000.));
+        assert_eq!(generator.state_at(1.5).states[0].extendx, 1750.);
+    }
+
+    #[test]
+    fn sheets() {
+        let selector = UnitSelector::Unit((12, UnitForm::Form3));
+        assert_eq!(selector.sheet_filename(0), selector.filename());
+        assert_eq!(selector.sheet_imgcut(1), "unit/012/s/012_s_01.imgcut");
+        assert_eq!(UnitSelector::Enemy(3).sheet_image(12), "enemy/003/003_e_12.png");
+
+        let models = Mamodels::from_reader(
+            &b"[modelanim:model2]\n1\n2\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,1,3,1,0,0,0,0,1000,1000,0,1000,0\n\
+            1000,3600,1000\n"[..],
+        )
+        .unwrap();
+        let maanim = Maanim::from_reader(
+            &b"[modelanim:animation2]\n1\n1\n\
+            1,1,-1,0,0\n3\n0,1,1,0\n2,0,1,0\n4,2,0,0\n"[..],
+        )
+        .unwrap();
+        let base = UnitState::from_model(&models);
+        assert_eq!((base.states[0].sheet, base.states[1].sheet), (-1, 1));
+        let generator = StateGenerator::from_anim(maanim, &models);
+        let sheets: Vec<_> = (0..6)
+            .map(|frame| generator.state_at(frame as f32).states[1].sheet)
+            .collect();
+        assert_eq!(sheets, [1, 1, 0, 0, 1, 1]);
+        assert_eq!(sheet_index(-1), 0);
+    }
+
+    #[test]
+    fn png_header() {
+        let mut header = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        header.extend(512u32.to_be_bytes());
+        header.extend(256u32.to_be_bytes());
+        assert_eq!(png_size(&header), Some((512, 256)));
+        assert_eq!(png_size(&header[..20]), None);
+        header[1] = b'J';
+        assert_eq!(png_size(&header), None);
+    }
+
+    #[test]
+    fn extent() {
+        let (_, imgcuts) = Imgcut::from_str("[imgcut]\n0\na.png\n2\n0,0,8,4\n8,2,4,10\n").unwrap();
+        assert_eq!(imgcut_extent(&imgcuts), (12, 12));
+        assert_eq!(imgcut_extent(&[]), (1, 1));
+    }
+}
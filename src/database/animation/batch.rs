@@ -0,0 +1,286 @@
+//! 1ユニットを動的なメッシュでまとめて描く
+//!
+//! パーツごとのエンティティは作らず、毎フレームCPUで全パーツの四角形の頂点を計算する。
+//! 合成方法(GlowType)とテクスチャはパーツごとには変えられないので、描く順に並べた四角形を
+//! (シート、GlowType)が同じものが続く区間に分けて、区間ごとに1つのメッシュで描く。
+//! 普通のユニットなら描画は1〜2回で済み、発光するパーツの前後関係もパーツごとに描くのと同じになる。
+
+use bevy::{
+    prelude::*,
+    render::{mesh::Indices, render_resource::PrimitiveTopology, view::NoFrustumCulling},
+    sprite::MaterialMesh2dBundle,
+};
+
+use super::{
+    solver::{draw_order, solve},
+    state_gen::from_data::StateGenerator,
+    UnitImage, UnitImages, UnitState,
+};
+use crate::{database::GlowType, material::Glow1Material};
+
+/// 区間ごとのメッシュをずらすz(ユニット内の前後関係にだけ使う)
+const LAYER_DEPTH: f32 = 0.001;
+
+/// まとめて描くユニット
+/// update_unit_batchが毎フレームメッシュを作り直す
+#[derive(Component)]
+pub struct UnitBatch {
+    /// UnitImages.imagesの番号
+    image: usize,
+    /// (シート、GlowType)ごとのマテリアル(使うときに作る)
+    materials: Vec<((usize, GlowType), Handle<Glow1Material>)>,
+    /// 区間ごとのメッシュ(足りなくなったら増やして、余った分は隠す)
+    layers: Vec<BatchLayer>,
+}
+
+/// メッシュ1つ分(子エンティティ)
+struct BatchLayer {
+    entity: Entity,
+    mesh: Handle<Mesh>,
+}
+
+/// パーツの四角形1つ分(座標はユニットのエンティティから見たもの)
+#[derive(Clone, Debug, PartialEq)]
+struct PartQuad {
+    sheet: usize,
+    glow: GlowType,
+    /// 左下、左上、右上、右下(shape::Quadと同じ順)
+    corners: [Vec2; 4],
+    uvs: [[f32; 2]; 4],
+    opacity: f32,
+    /// 親を辿って足したz(小さい方から描く)
+    depth: f32,
+}
+
+impl UnitBatch {
+    /// unitをまとめて描くようにする
+    /// メッシュの子エンティティはupdate_unit_batchで作る
+    pub fn spawn(commands: &mut Commands, unit: Entity, image_index: usize) {
+        commands.entity(unit).insert(UnitBatch {
+            image: image_index,
+            materials: Vec::new(),
+            layers: Vec::new(),
+        });
+    }
+
+    fn material(
+        &mut self,
+        key: (usize, GlowType),
+        image: &UnitImage,
+        glow_materials: &mut Assets<Glow1Material>,
+    ) -> Handle<Glow1Material> {
+        if let Some((_, material)) = self.materials.iter().find(|(k, _)| *k == key) {
+            return material.clone();
+        }
+        let (sheet, glow) = key;
+        let material = glow_materials.add(Glow1Material::new(image.sheets[sheet].texture.clone(), glow));
+        self.materials.push((key, material.clone()));
+        material
+    }
+}
+
+/// 描く順に並べた画像のあるパーツの四角形
+fn part_quads(state: &UnitState, image: &UnitImage) -> Vec<PartQuad> {
+    let parts = solve(state, image);
+    draw_order(&parts)
+        .into_iter()
+        .filter_map(|i| {
+            let part = &parts[i];
+            let unit_sheet = &image.sheets[part.sheet];
+            let imgcut = &unit_sheet.imgcuts[part.img?];
+            let corners = [(-0.5, -0.5), (-0.5, 0.5), (0.5, 0.5), (0.5, -0.5)]
+                .map(|(x, y)| part.image.transform_point3(Vec3::new(x, y, 0.)).truncate());
+            let texture = unit_sheet.texture_size;
+            let (w, h) = (texture.width as f32, texture.height as f32);
+            let (u, v) = (imgcut.x as f32 / w, imgcut.y as f32 / h);
+            let (u2, v2) = (
+                (imgcut.x + imgcut.width) as f32 / w,
+                (imgcut.y + imgcut.height) as f32 / h,
+            );
+            Some(PartQuad {
+                sheet: part.sheet,
+                glow: part.glow,
+                corners,
+                uvs: [[u, v2], [u, v], [u2, v], [u2, v2]],
+                opacity: part.opacity,
+                depth: part.depth,
+            })
+        })
+        .collect()
+}
+
+/// (シート、GlowType)が同じものが続く区間に分ける(描く順はそのまま)
+fn runs(quads: &[PartQuad]) -> Vec<&[PartQuad]> {
+    let mut runs = Vec::new();
+    let mut start = 0;
+    for i in 1..=quads.len() {
+        let end = quads
+            .get(i)
+            .is_none_or(|quad| (quad.sheet, quad.glow) != (quads[start].sheet, quads[start].glow));
+        if end {
+            runs.push(&quads[start..i]);
+            start = i;
+        }
+    }
+    runs
+}
+
+/// 頂点色のアルファに不透明度を入れる
+fn layer_mesh(quads: &[PartQuad]) -> Mesh {
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    let positions: Vec<[f32; 3]> = quads
+        .iter()
+        .flat_map(|quad| quad.corners.map(|c| [c.x, c.y, 0.]))
+        .collect();
+    let uvs: Vec<[f32; 2]> = quads.iter().flat_map(|quad| quad.uvs).collect();
+    let colors: Vec<[f32; 4]> = quads
+        .iter()
+        .flat_map(|quad| [[1., 1., 1., quad.opacity]; 4])
+        .collect();
+    let indices = (0..quads.len() as u32)
+        .flat_map(|i| [0, 2, 1, 0, 3, 2].map(|k| i * 4 + k))
+        .collect();
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh
+}
+
+/// 今の状態で区間ごとのメッシュを作り直す(使わないメッシュは隠す)
+pub fn update_unit_batch(
+    mut commands: Commands,
+    mut units: Query<(Entity, &StateGenerator, &mut UnitBatch)>,
+    mut layers: Query<(&mut Visibility, &mut Handle<Glow1Material>)>,
+    image_data: Res<UnitImages>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut glow_materials: ResMut<Assets<Glow1Material>>,
+) {
+    for (unit, generator, mut batch) in &mut units {
+        let Some(Some(image)) = image_data.images.get(batch.image) else {
+            continue;
+        };
+        let quads = part_quads(&generator.current_state(), image);
+        let runs = runs(&quads);
+        for (i, run) in runs.iter().enumerate() {
+            let material = batch.material((run[0].sheet, run[0].glow), image, &mut glow_materials);
+            let Some(layer) = batch.layers.get(i) else {
+                let mesh = meshes.add(layer_mesh(run));
+                let entity = commands
+                    .spawn(MaterialMesh2dBundle {
+                        mesh: mesh.clone().into(),
+                        material,
+                        transform: Transform::from_xyz(0., 0., i as f32 * LAYER_DEPTH),
+                        ..default()
+                    })
+                    // 頂点が毎フレーム変わるので最初のAabbで間引かれないようにする
+                    .insert(NoFrustumCulling)
+                    .set_parent(unit)
+                    .id();
+                batch.layers.push(BatchLayer { entity, mesh });
+                continue;
+            };
+            if let Ok((mut visibility, mut handle)) = layers.get_mut(layer.entity) {
+                *visibility = Visibility::Inherited;
+                if *handle != material {
+                    *handle = material;
+                }
+            }
+            if let Some(mesh) = meshes.get_mut(&layer.mesh) {
+                *mesh = layer_mesh(run);
+            }
+        }
+        for layer in batch.layers.iter().skip(runs.len()) {
+            if let Ok((mut visibility, _)) = layers.get_mut(layer.entity) {
+                *visibility = Visibility::Hidden;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::animation::solver::test::image;
+
+    fn assert_near(a: Vec2, b: Vec2) {
+        assert!(a.abs_diff_eq(b, 1e-3), "{a} != {b}");
+    }
+
+    #[test]
+    fn quads() {
+        // 1: pivot(5,5)で(100,50)に置いて90度回す
+        // 2: 1の子で、後ろ(zorder 0)に置いて左右反転する、半透明の加算
+        let image = image(
+            "[modelanim:model2]\n1\n3\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,1,100,50,5,5,1000,1000,900,1000,0\n\
+            1,0,1,0,0,0,0,0,-1000,1000,0,500,1\n\
+            1000,3600,1000\n",
+        );
+        let state = UnitState::from_model(&image.mamodels);
+        let quads = part_quads(&state, &image);
+        assert_eq!(quads.len(), 2);
+        let (child, part) = (&quads[0], &quads[1]);
+        assert_eq!((part.glow, part.opacity), (GlowType::None, 1.));
+        assert_eq!(part.uvs, [[0., 0.5], [0., 0.], [0.5, 0.], [0.5, 0.5]]);
+        // 時計回りに90度回るので、pivotから見て右下(15,-5)にあった角は左下に来る
+        assert_near(part.corners[3], Vec2::new(95., -65.));
+        assert_near(part.corners[1], Vec2::new(105., -45.));
+
+        assert_eq!((child.glow, child.opacity), (GlowType::Black, 0.5));
+        assert!(child.depth < part.depth);
+        // 左右反転した10x10の画像がpivot(0,0)から広がり、親と一緒に回る
+        assert_near(child.corners[1], Vec2::new(100., -50.));
+        assert_near(child.corners[3], Vec2::new(90., -40.));
+
+        let mesh = layer_mesh(&quads);
+        assert_eq!(mesh.count_vertices(), 8);
+        assert_eq!(mesh.indices().unwrap().len(), 12);
+    }
+
+    #[test]
+    fn glow_runs() {
+        // 通常、加算、通常の順に重なっていれば、加算のパーツは2つの通常のパーツの間に描く
+        let sandwich = image(
+            "[modelanim:model2]\n1\n4\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,1,1,0,0,0,0,1000,1000,0,1000,1\n\
+            0,0,0,2,0,0,0,0,1000,1000,0,1000,0\n\
+            1000,3600,1000\n",
+        );
+        let quads = part_quads(&UnitState::from_model(&sandwich.mamodels), &sandwich);
+        let glows: Vec<_> = runs(&quads).iter().map(|run| (run.len(), run[0].glow)).collect();
+        assert_eq!(glows, [(1, GlowType::None), (1, GlowType::Black), (1, GlowType::None)]);
+
+        // 同じ合成方法が続けば1つにまとめる
+        let image = image(
+            "[modelanim:model2]\n1\n4\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,1,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,1,2,0,0,0,0,1000,1000,0,1000,1\n\
+            1000,3600,1000\n",
+        );
+        let quads = part_quads(&UnitState::from_model(&image.mamodels), &image);
+        assert_eq!(runs(&quads).iter().map(|run| run.len()).collect::<Vec<_>>(), [2, 1]);
+        assert!(runs(&[]).is_empty());
+    }
+
+    #[test]
+    fn parent_order() {
+        // 親の方が後ろの番号でも、親が無い(範囲外の)パーツがあってもよい
+        let image = image(
+            "[modelanim:model2]\n1\n3\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            2,0,0,1,10,0,0,0,1000,1000,0,1000,0\n\
+            7,0,0,0,0,20,0,0,1000,1000,0,1000,0\n\
+            1000,3600,1000\n",
+        );
+        let quads = part_quads(&UnitState::from_model(&image.mamodels), &image);
+        assert_eq!(quads.len(), 2);
+        assert_near(quads[0].corners[1], Vec2::new(0., -20.));
+        assert_near(quads[1].corners[1], Vec2::new(10., -20.));
+    }
+}
@@ -0,0 +1,347 @@
+//! アニメーションをGIF、APNG、アニメーションWebPに書き出す
+//!
+//! 各フレームはrasterで描き、全フレームを同じ大きさにそろえる。
+//! 時間は元のアニメーションの30fpsを基準にして、fpsに合わせて間引く(増やす)。
+
+use std::{fs::File, io::{BufWriter, Write}, path::Path};
+
+use bevy::math::{Rect, Vec2};
+use image::{
+    codecs::gif::{GifEncoder, Repeat},
+    Delay, Frame, RgbaImage,
+};
+
+use super::{
+    raster::{HeadlessUnit, View},
+    state_gen::{from_data::StateGenerator, Maanim},
+    UnitState,
+};
+use crate::database::error::{Error, ErrorKind};
+
+/// アニメーションのフレームレート
+const ANIM_FPS: f32 = 30.;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Gif,
+    Apng,
+    WebP,
+}
+
+impl ExportFormat {
+    /// 拡張子(gif、png、apng、webp)から決める
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let ext = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "gif" => Some(Self::Gif),
+            "png" | "apng" => Some(Self::Apng),
+            "webp" => Some(Self::WebP),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExportOptions {
+    /// アルファが0なら透明(GIFは半透明を表せないので、アルファの半分で切る)
+    pub background: [u8; 4],
+    /// 1単位を何画素にするか
+    pub scale: f32,
+    /// trueなら全フレームを囲む範囲で切り抜く、falseならユニットの原点(足元)も含める
+    pub crop: bool,
+    /// 周りの余白(画素)
+    pub margin: u32,
+    /// 書き出すフレームレート
+    pub fps: f32,
+    /// 何周分書き出すか
+    pub loops: u32,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            background: [0; 4],
+            scale: 1.,
+            crop: true,
+            margin: 4,
+            fps: ANIM_FPS,
+            loops: 1,
+        }
+    }
+}
+
+impl ExportOptions {
+    /// 1フレームの表示時間(ミリ秒)
+    fn delay_ms(&self) -> u32 {
+        (1000. / self.fps).round().max(1.) as u32
+    }
+}
+
+/// 書き出す各フレームの状態(periodが0なら1フレーム)
+pub fn sample_states(maanim: Maanim, unit: &HeadlessUnit, options: &ExportOptions) -> Vec<UnitState> {
+    let period = maanim.period();
+    let generator = StateGenerator::from_anim(maanim, &unit.image.mamodels);
+    let step = ANIM_FPS / options.fps;
+    let count = ((period * options.loops) as f32 / step).ceil().max(1.) as u32;
+    (0..count)
+        .map(|k| generator.state_at(k as f32 * step))
+        .collect()
+}
+
+/// 全フレームを同じViewで描く
+pub fn render_frames(unit: &HeadlessUnit, states: &[UnitState], options: &ExportOptions) -> Vec<RgbaImage> {
+    let origin = (!options.crop).then_some(Rect::from_corners(Vec2::ZERO, Vec2::ZERO));
+    let bounds = states
+        .iter()
+        .filter_map(|state| unit.bounds(state))
+        .chain(origin)
+        .reduce(|a, b| a.union(b))
+        .unwrap_or_default();
+    let view = View {
+        background: options.background,
+        ..View::fit(bounds, options.scale, options.margin)
+    };
+    states.iter().map(|state| unit.render(state, &view)).collect()
+}
+
+fn encode_error(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::new(ErrorKind::IOError, e)
+}
+
+/// framesは全て同じ大きさであること
+pub fn encode<W: Write>(
+    writer: W,
+    frames: Vec<RgbaImage>,
+    format: ExportFormat,
+    options: &ExportOptions,
+) -> Result<(), Error> {
+    match format {
+        ExportFormat::Gif => encode_gif(writer, frames, options),
+        ExportFormat::Apng => encode_apng(writer, frames, options),
+        ExportFormat::WebP => encode_webp(writer, frames, options),
+    }
+}
+
+fn encode_gif<W: Write>(writer: W, frames: Vec<RgbaImage>, options: &ExportOptions) -> Result<(), Error> {
+    let mut encoder = GifEncoder::new(writer);
+    encoder.set_repeat(Repeat::Infinite).map_err(encode_error)?;
+    let delay = Delay::from_numer_denom_ms(options.delay_ms(), 1);
+    encoder
+        .encode_frames(frames.into_iter().map(|mut frame| {
+            // GIFは1bitの透過しか無い
+            for pixel in frame.pixels_mut() {
+                pixel[3] = if pixel[3] >= 128 { 255 } else { 0 };
+            }
+            Frame::from_parts(frame, 0, 0, delay)
+        }))
+        .map_err(encode_error)
+}
+
+fn encode_apng<W: Write>(writer: W, frames: Vec<RgbaImage>, options: &ExportOptions) -> Result<(), Error> {
+    let (width, height) = frames.first().map_or((1, 1), |frame| frame.dimensions());
+    let mut encoder = png::Encoder::new(writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(frames.len() as u32, 0)
+        .map_err(encode_error)?;
+    encoder
+        .set_frame_delay(options.delay_ms().min(u16::MAX as u32) as u16, 1000)
+        .map_err(encode_error)?;
+    encoder.set_blend_op(png::BlendOp::Source).map_err(encode_error)?;
+    let mut writer = encoder.write_header().map_err(encode_error)?;
+    for frame in &frames {
+        writer.write_image_data(frame).map_err(encode_error)?;
+    }
+    writer.finish().map_err(encode_error)
+}
+
+/// RIFFのチャンク(奇数の長さなら0を1バイト足す)
+fn write_chunk(buf: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    buf.extend(name);
+    buf.extend((data.len() as u32).to_le_bytes());
+    buf.extend(data);
+    if data.len() % 2 == 1 {
+        buf.push(0);
+    }
+}
+
+fn u24(v: u32) -> [u8; 3] {
+    let [a, b, c, _] = v.to_le_bytes();
+    [a, b, c]
+}
+
+/// 各フレームを可逆(VP8L)で圧縮して、ANMFチャンクに入れる
+fn encode_webp<W: Write>(mut writer: W, frames: Vec<RgbaImage>, options: &ExportOptions) -> Result<(), Error> {
+    let (width, height) = frames.first().map_or((1, 1), |frame| frame.dimensions());
+    let mut body = Vec::new();
+    let mut vp8x = vec![0b0001_0010, 0, 0, 0];
+    vp8x.extend(u24(width - 1));
+    vp8x.extend(u24(height - 1));
+    write_chunk(&mut body, b"VP8X", &vp8x);
+    let [r, g, b, a] = options.background;
+    let mut anim = vec![b, g, r, a];
+    anim.extend(0u16.to_le_bytes());
+    write_chunk(&mut body, b"ANIM", &anim);
+
+    for frame in &frames {
+        let mut webp = Vec::new();
+        image_webp::WebPEncoder::new(&mut webp)
+            .encode(frame, width, height, image_webp::ColorType::Rgba8)
+            .map_err(encode_error)?;
+        // 単純な形式("RIFF" size "WEBP" "VP8L" size data)からVP8Lのチャンクを取り出す
+        let vp8l = webp
+            .get(12..)
+            .filter(|chunk| chunk.starts_with(b"VP8L"))
+            .ok_or_else(|| encode_error("VP8Lのチャンクが無い"))?;
+        let mut anmf = Vec::with_capacity(16 + vp8l.len());
+        anmf.extend(u24(0));
+        anmf.extend(u24(0));
+        anmf.extend(u24(width - 1));
+        anmf.extend(u24(height - 1));
+        anmf.extend(u24(options.delay_ms()));
+        // 前のフレームと混ぜずに置き換える
+        anmf.push(0b10);
+        anmf.extend(vp8l);
+        write_chunk(&mut body, b"ANMF", &anmf);
+    }
+
+    writer.write_all(b"RIFF").map_err(encode_error)?;
+    writer
+        .write_all(&(body.len() as u32 + 4).to_le_bytes())
+        .map_err(encode_error)?;
+    writer.write_all(b"WEBP").map_err(encode_error)?;
+    writer.write_all(&body).map_err(encode_error)
+}
+
+/// 形式はpathの拡張子で決める
+pub fn export<P: AsRef<Path>>(
+    unit: &HeadlessUnit,
+    maanim: Maanim,
+    options: &ExportOptions,
+    path: P,
+) -> Result<(), Error> {
+    let path = path.as_ref();
+    let format = ExportFormat::from_path(path).ok_or_else(|| {
+        Error::new(ErrorKind::InvalidData, "拡張子がgif、png、apng、webpのどれでもない").with_path(path)
+    })?;
+    let frames = render_frames(unit, &sample_states(maanim, unit, options), options);
+    let file = File::create(path).map_err(|e| Error::from(e).with_path(path))?;
+    encode(BufWriter::new(file), frames, format, options).map_err(|e| e.with_path(path))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::animation::solver::test::image;
+    use image::{
+        codecs::{gif::GifDecoder, png::PngDecoder},
+        AnimationDecoder, Rgba,
+    };
+
+    fn unit() -> HeadlessUnit {
+        let texture = RgbaImage::from_fn(40, 20, |x, y| match (x, y) {
+            (0..=19, 0..=9) => Rgba([255, 0, 0, 255]),
+            _ => Rgba([0, 0, 0, 0]),
+        });
+        HeadlessUnit::new(
+            image(
+                "[modelanim:model2]\n1\n2\n\
+                -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+                0,0,0,0,0,0,10,5,1000,1000,0,1000,0\n\
+                1000,3600,1000\n",
+            ),
+            vec![texture],
+        )
+    }
+
+    /// 10フレームで右に100動く
+    fn maanim() -> Maanim {
+        Maanim::from_reader(
+            &b"[modelanim:animation2]\n1\n1\n\
+            1,4,-1,0,0\n2\n0,0,0,0\n10,100,0,0\n"[..],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn frames() {
+        let unit = unit();
+        let options = ExportOptions {
+            fps: 15.,
+            loops: 2,
+            ..default_options()
+        };
+        let states = sample_states(maanim(), &unit, &options);
+        assert_eq!(states.len(), 10);
+        let frames = render_frames(&unit, &states, &options);
+        // 2フレームおきで周期10なので右端は80、全フレームを囲む範囲(-10〜90)に余白を付ける
+        assert_eq!(frames[0].dimensions(), (100 + 8, 10 + 8));
+        assert!(frames.iter().all(|frame| frame.dimensions() == frames[0].dimensions()));
+        assert_eq!(frames[0].get_pixel(5, 9), &Rgba([255, 0, 0, 255]));
+        assert_eq!(frames[1].get_pixel(5, 9), &Rgba([0, 0, 255, 255]));
+
+        // 3フレーム目は30〜50にあり、切り抜かないなら足元の原点まで広げる
+        let frames = render_frames(&unit, &states[2..3], &options);
+        assert_eq!(frames[0].dimensions(), (20 + 8, 10 + 8));
+        let uncropped = ExportOptions {
+            crop: false,
+            ..options
+        };
+        let frames = render_frames(&unit, &states[2..3], &uncropped);
+        assert_eq!(frames[0].dimensions(), (50 + 8, 10 + 8));
+
+        let still = Maanim::from_reader(&b"[modelanim:animation2]\n1\n0\n"[..]).unwrap();
+        assert_eq!(sample_states(still, &unit, &options).len(), 1);
+    }
+
+    fn default_options() -> ExportOptions {
+        ExportOptions {
+            background: [0, 0, 255, 255],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let unit = unit();
+        let options = ExportOptions {
+            fps: 10.,
+            ..default_options()
+        };
+        let frames = render_frames(&unit, &sample_states(maanim(), &unit, &options), &options);
+        assert_eq!(frames.len(), 4);
+
+        let mut gif = Vec::new();
+        encode(&mut gif, frames.clone(), ExportFormat::Gif, &options).unwrap();
+        let decoded = GifDecoder::new(gif.as_slice()).unwrap().into_frames().collect_frames().unwrap();
+        assert_eq!(decoded.len(), 4);
+        assert_eq!(decoded[0].delay().numer_denom_ms(), (100, 1));
+        assert_eq!(decoded[2].buffer().get_pixel(0, 0), &Rgba([0, 0, 255, 255]));
+
+        let mut apng = Vec::new();
+        encode(&mut apng, frames.clone(), ExportFormat::Apng, &options).unwrap();
+        let decoder = png::Decoder::new(apng.as_slice()).read_info().unwrap();
+        let info = decoder.info();
+        assert_eq!(info.animation_control.map(|a| a.num_frames), Some(4));
+        assert_eq!((info.width, info.height), frames[0].dimensions());
+        assert!(PngDecoder::new(apng.as_slice()).is_ok());
+
+        let mut webp = Vec::new();
+        encode(&mut webp, frames.clone(), ExportFormat::WebP, &options).unwrap();
+        assert_eq!(&webp[..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize, webp.len() - 8);
+        let mut decoder = image_webp::WebPDecoder::new(std::io::Cursor::new(webp)).unwrap();
+        assert!(decoder.is_animated());
+        assert_eq!(decoder.dimensions(), frames[0].dimensions());
+        let mut buf = vec![0; frames[0].len()];
+        for frame in &frames {
+            decoder.read_frame(&mut buf).unwrap();
+            assert_eq!(&buf, frame.as_raw());
+        }
+
+        assert_eq!(ExportFormat::from_path("a/b.WEBP"), Some(ExportFormat::WebP));
+        assert_eq!(ExportFormat::from_path("a.apng"), Some(ExportFormat::Apng));
+        assert_eq!(ExportFormat::from_path("a.jpg"), None);
+    }
+}
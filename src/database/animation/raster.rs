@@ -0,0 +1,390 @@
+//! GPUを使わずにユニットの1フレームをRGBAの画像に描く
+//!
+//! パーツの変換はsolveで求め、合成方法はGlow1Materialのblend_stateと同じ式を使う。
+//! 途中の画素はアルファを掛けた値で持ち、最後に戻してPNGなどで保存できるようにする。
+//! 色はsRGBのまま合成するので、GPUで描いたものとは半透明の部分が少し違う。
+
+use std::path::Path;
+
+use bevy::math::{Mat2, Rect, Vec2, Vec4};
+use image::{ImageFormat, Rgba, RgbaImage};
+
+use super::{
+    solver::{draw_order, solve},
+    Size2d, UnitImage, UnitSelector, UnitSheet, UnitState,
+};
+use crate::database::{
+    error::{Error, ErrorKind},
+    source::AssetSource,
+    GlowType, Imgcut,
+};
+
+/// 描く範囲と倍率
+#[derive(Clone, Debug, PartialEq)]
+pub struct View {
+    pub width: u32,
+    pub height: u32,
+    /// ユニットの原点を置く画素の位置(左上が(0,0))
+    pub origin: Vec2,
+    /// 1単位を何画素にするか
+    pub scale: f32,
+    /// アルファが0なら透明
+    pub background: [u8; 4],
+}
+
+impl View {
+    /// bounds(ユニットの座標)がmargin画素の余白を付けて収まる大きさ
+    pub fn fit(bounds: Rect, scale: f32, margin: u32) -> Self {
+        let margin = margin as f32;
+        Self {
+            width: (bounds.width() * scale + margin * 2.).ceil().max(1.) as u32,
+            height: (bounds.height() * scale + margin * 2.).ceil().max(1.) as u32,
+            origin: Vec2::new(
+                margin - bounds.min.x * scale,
+                margin + bounds.max.y * scale,
+            ),
+            scale,
+            background: [0; 4],
+        }
+    }
+
+    fn to_pixel(&self, p: Vec2) -> Vec2 {
+        Vec2::new(self.origin.x + p.x * self.scale, self.origin.y - p.y * self.scale)
+    }
+}
+
+/// GPUを使わずに描くための1ユニット分のデータ
+pub struct HeadlessUnit {
+    /// GPUのリソース(ハンドル)は空のまま
+    pub image: UnitImage,
+    /// シートの番号順
+    pub textures: Vec<RgbaImage>,
+}
+
+fn decode_png(bytes: &[u8], path: &str) -> Result<RgbaImage, Error> {
+    image::load_from_memory_with_format(bytes, ImageFormat::Png)
+        .map(|image| image.to_rgba8())
+        .map_err(|e| Error::new(ErrorKind::FileFormatError, e).with_path(path))
+}
+
+impl HeadlessUnit {
+    pub fn new(image: UnitImage, textures: Vec<RgbaImage>) -> Self {
+        Self { image, textures }
+    }
+
+    /// mamodel、全シートのimgcutと画像を読み込む
+    pub fn load(source: &dyn AssetSource, selector: UnitSelector) -> Result<Self, Error> {
+        let mamodels = selector.load_mamodel(source)?;
+        let mut imgcuts = vec![selector.load_imgcut(source)?];
+        for k in 1.. {
+            let path = selector.sheet_imgcut(k);
+            if !source.is_file(Path::new(&path)) {
+                break;
+            }
+            imgcuts.push(Imgcut::load(source, &path)?.1);
+        }
+        let mut sheets = Vec::with_capacity(imgcuts.len());
+        let mut textures = Vec::with_capacity(imgcuts.len());
+        for (k, imgcuts) in imgcuts.into_iter().enumerate() {
+            let path = selector.sheet_image(k);
+            let texture = decode_png(&source.read(Path::new(&path))?, &path)?;
+            sheets.push(UnitSheet {
+                size: imgcuts.iter().cloned().map(Size2d::from).collect(),
+                meshes: Vec::new(),
+                imgcuts,
+                texture: Default::default(),
+                texture_size: Size2d {
+                    width: texture.width(),
+                    height: texture.height(),
+                },
+            });
+            textures.push(texture);
+        }
+        Ok(Self::new(
+            UnitImage {
+                materials: Vec::new(),
+                sheets,
+                mamodels,
+            },
+            textures,
+        ))
+    }
+
+    /// 画像のある全パーツを囲む長方形(ユニットの座標)
+    /// 描くパーツが無ければNone
+    pub fn bounds(&self, state: &UnitState) -> Option<Rect> {
+        solve(state, &self.image)
+            .iter()
+            .filter(|part| part.img.is_some())
+            .flat_map(|part| {
+                [(-0.5, -0.5), (-0.5, 0.5), (0.5, 0.5), (0.5, -0.5)]
+                    .map(|(x, y)| part.image.transform_point3([x, y, 0.].into()).truncate())
+            })
+            .fold(None, |rect: Option<Rect>, p| {
+                Some(rect.map_or(Rect::from_corners(p, p), |rect| {
+                    rect.union_point(p)
+                }))
+            })
+    }
+
+    pub fn render(&self, state: &UnitState, view: &View) -> RgbaImage {
+        let mut canvas = Canvas::new(view);
+        let parts = solve(state, &self.image);
+        for part in draw_order(&parts).into_iter().map(|i| &parts[i]) {
+            let (Some(img), Some(texture)) = (part.img, self.textures.get(part.sheet)) else {
+                continue;
+            };
+            let imgcut = &self.image.sheets[part.sheet].imgcuts[img];
+            let origin = view.to_pixel(part.image.translation.truncate());
+            let axes = Mat2::from_cols(
+                view.to_pixel(part.image.matrix3.x_axis.truncate()) - view.origin,
+                view.to_pixel(part.image.matrix3.y_axis.truncate()) - view.origin,
+            );
+            canvas.draw_quad(texture, imgcut, origin, axes, part.opacity, part.glow);
+        }
+        canvas.into_image()
+    }
+}
+
+/// アルファを掛けた値(0〜1)で持つ画素
+struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<Vec4>,
+}
+
+impl Canvas {
+    fn new(view: &View) -> Self {
+        let [r, g, b, a] = view.background.map(|v| v as f32 / 255.);
+        Self {
+            width: view.width,
+            height: view.height,
+            pixels: vec![Vec4::new(r * a, g * a, b * a, a); (view.width * view.height) as usize],
+        }
+    }
+
+    /// 四角形(-0.5〜0.5)をoriginとaxesで画素の座標に置いて描く
+    fn draw_quad(
+        &mut self,
+        texture: &RgbaImage,
+        imgcut: &Imgcut,
+        origin: Vec2,
+        axes: Mat2,
+        opacity: f32,
+        glow: GlowType,
+    ) {
+        if axes.determinant().abs() < 1e-6 || imgcut.width == 0 || imgcut.height == 0 {
+            return;
+        }
+        let inverse = axes.inverse();
+        let corners = [(-0.5, -0.5), (-0.5, 0.5), (0.5, 0.5), (0.5, -0.5)]
+            .map(|(x, y)| origin + axes * Vec2::new(x, y));
+        let min = corners.iter().fold(Vec2::splat(f32::MAX), |a, &b| a.min(b));
+        let max = corners.iter().fold(Vec2::splat(f32::MIN), |a, &b| a.max(b));
+        let xs = min.x.floor().max(0.) as u32..(max.x.ceil().max(0.) as u32).min(self.width);
+        let ys = min.y.floor().max(0.) as u32..(max.y.ceil().max(0.) as u32).min(self.height);
+        for y in ys {
+            for x in xs.clone() {
+                let local = inverse * (Vec2::new(x as f32 + 0.5, y as f32 + 0.5) - origin);
+                if local.x.abs() > 0.5 || local.y.abs() > 0.5 {
+                    continue;
+                }
+                // 四角形のyは上向き、画像のyは下向き
+                let src = sample(
+                    texture,
+                    imgcut,
+                    imgcut.x as f32 + (local.x + 0.5) * imgcut.width as f32,
+                    imgcut.y as f32 + (0.5 - local.y) * imgcut.height as f32,
+                );
+                let dst = &mut self.pixels[(y * self.width + x) as usize];
+                *dst = blend(src, *dst, opacity, glow);
+            }
+        }
+    }
+
+    fn into_image(self) -> RgbaImage {
+        let mut image = RgbaImage::new(self.width, self.height);
+        for (pixel, v) in image.pixels_mut().zip(self.pixels) {
+            let rgb = if v.w > 0. { v.truncate() / v.w } else { v.truncate() };
+            let [r, g, b, a] = rgb.extend(v.w).to_array().map(|c| (c.clamp(0., 1.) * 255.).round() as u8);
+            *pixel = Rgba([r, g, b, a]);
+        }
+        image
+    }
+}
+
+/// imgcutの範囲から出ないように双線形補間する(アルファを掛けた値)
+fn sample(texture: &RgbaImage, imgcut: &Imgcut, x: f32, y: f32) -> Vec4 {
+    let texel = |x: i64, y: i64| {
+        let x = x.clamp(imgcut.x as i64, (imgcut.x + imgcut.width) as i64 - 1);
+        let y = y.clamp(imgcut.y as i64, (imgcut.y + imgcut.height) as i64 - 1);
+        match texture.get_pixel_checked(x as u32, y as u32) {
+            Some(&Rgba([r, g, b, a])) => {
+                let a = a as f32 / 255.;
+                Vec4::new(r as f32 / 255. * a, g as f32 / 255. * a, b as f32 / 255. * a, a)
+            }
+            None => Vec4::ZERO,
+        }
+    };
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (tx, ty) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let top = texel(x0, y0).lerp(texel(x0 + 1, y0), tx);
+    let bottom = texel(x0, y0 + 1).lerp(texel(x0 + 1, y0 + 1), tx);
+    top.lerp(bottom, ty)
+}
+
+/// Glow1Materialのblend_stateと同じ合成(srcとdstはアルファを掛けた値)
+fn blend(src: Vec4, dst: Vec4, opacity: f32, glow: GlowType) -> Vec4 {
+    let alpha = src.w * opacity;
+    let color = src.truncate() * opacity;
+    let (rgb, a) = match glow {
+        GlowType::None => (color + dst.truncate() * (1. - alpha), alpha + dst.w * (1. - alpha)),
+        GlowType::Black => (color + dst.truncate(), alpha + dst.w),
+        GlowType::White => (dst.truncate() - color, dst.w),
+        // srcは乗算済みなので、反転する量はsrcのアルファと不透明度で決まる
+        GlowType::Inverse => (
+            color * (1. - dst.truncate()) + dst.truncate() * (1. - alpha),
+            dst.w,
+        ),
+    };
+    rgb.extend(a).clamp(Vec4::ZERO, Vec4::ONE)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{animation::solver::test::image, source::MemorySource};
+
+    /// 0番(20x10)は左半分が赤、右半分が緑、1番(10x10)は半透明の白
+    fn texture() -> RgbaImage {
+        RgbaImage::from_fn(40, 20, |x, y| match (x, y) {
+            (0..=9, 0..=9) => Rgba([255, 0, 0, 255]),
+            (10..=19, 0..=9) => Rgba([0, 255, 0, 255]),
+            (20..=29, 0..=9) => Rgba([255, 255, 255, 128]),
+            _ => Rgba([0, 0, 0, 0]),
+        })
+    }
+
+    fn unit(mamodel: &str) -> HeadlessUnit {
+        HeadlessUnit::new(image(mamodel), vec![texture()])
+    }
+
+    fn view() -> View {
+        View {
+            width: 40,
+            height: 40,
+            origin: Vec2::new(20., 20.),
+            scale: 1.,
+            background: [0, 0, 255, 255],
+        }
+    }
+
+    #[test]
+    fn pivot_and_flip() {
+        // pivot(10,5)を原点に置き、左右反転する
+        let unit = unit(
+            "[modelanim:model2]\n1\n2\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,0,0,0,10,5,-1000,1000,0,1000,0\n\
+            1000,3600,1000\n",
+        );
+        let state = UnitState::from_model(&unit.image.mamodels);
+        let bounds = unit.bounds(&state).unwrap();
+        assert!(bounds.min.abs_diff_eq(Vec2::new(-10., -5.), 1e-4));
+        assert!(bounds.max.abs_diff_eq(Vec2::new(10., 5.), 1e-4));
+
+        let image = unit.render(&state, &view());
+        assert_eq!(image.get_pixel(12, 20), &Rgba([0, 255, 0, 255]));
+        assert_eq!(image.get_pixel(27, 20), &Rgba([255, 0, 0, 255]));
+        assert_eq!(image.get_pixel(20, 10), &Rgba([0, 0, 255, 255]));
+
+        let fit = View::fit(bounds, 2., 1);
+        assert_eq!((fit.width, fit.height, fit.origin), (42, 22, Vec2::new(21., 11.)));
+        let image = unit.render(&state, &fit);
+        assert_eq!(image.get_pixel(0, 0)[3], 0);
+        assert_eq!(image.get_pixel(2, 2), &Rgba([0, 255, 0, 255]));
+    }
+
+    #[test]
+    fn rotation_and_opacity() {
+        // 90度回して、半分の不透明度で描く
+        let unit = unit(
+            "[modelanim:model2]\n1\n2\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,0,0,0,10,5,1000,1000,900,500,0\n\
+            1000,3600,1000\n",
+        );
+        let image = unit.render(&UnitState::from_model(&unit.image.mamodels), &view());
+        // 時計回りに回るので、左半分(赤)は上に来る
+        assert_eq!(image.get_pixel(20, 12), &Rgba([128, 0, 128, 255]));
+        assert_eq!(image.get_pixel(20, 27), &Rgba([0, 128, 128, 255]));
+        assert_eq!(image.get_pixel(12, 20), &Rgba([0, 0, 255, 255]));
+    }
+
+    #[test]
+    fn glow() {
+        let src = Vec4::new(0.5, 0.5, 0.5, 0.5);
+        let dst = Vec4::new(0.2, 0.4, 0.6, 1.);
+        let add = blend(src, dst, 1., GlowType::Black);
+        assert!(add.abs_diff_eq(Vec4::new(0.7, 0.9, 1., 1.), 1e-6));
+        let sub = blend(src, dst, 1., GlowType::White);
+        assert!(sub.abs_diff_eq(Vec4::new(0., 0., 0.1, 1.), 1e-6));
+        let inverse = blend(Vec4::new(1., 1., 1., 1.), dst, 1., GlowType::Inverse);
+        assert!(inverse.abs_diff_eq(Vec4::new(0.8, 0.6, 0.4, 1.), 1e-6));
+        // 不透明度0なら下の色のまま、0.5なら反転した色との中間
+        assert_eq!(blend(Vec4::ONE, dst, 0., GlowType::Inverse), dst);
+        let half = blend(Vec4::ONE, dst, 0.5, GlowType::Inverse);
+        assert!(half.abs_diff_eq(Vec4::new(0.5, 0.5, 0.5, 1.), 1e-6));
+        // 半透明の画素も同じように弱まる
+        let faint = blend(src, dst, 1., GlowType::Inverse);
+        assert!(faint.abs_diff_eq(Vec4::new(0.5, 0.5, 0.5, 1.), 1e-6));
+        assert_eq!(blend(Vec4::ZERO, dst, 1., GlowType::Inverse), dst);
+        let normal = blend(src, dst, 0.5, GlowType::None);
+        assert!(normal.abs_diff_eq(Vec4::new(0.4, 0.55, 0.7, 1.), 1e-6));
+
+        // 透明な背景に加算したものは、保存するときにアルファで割る
+        let unit = unit(
+            "[modelanim:model2]\n1\n2\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,1,0,0,0,5,5,1000,1000,0,1000,1\n\
+            1000,3600,1000\n",
+        );
+        let view = View {
+            background: [0; 4],
+            ..view()
+        };
+        let image = unit.render(&UnitState::from_model(&unit.image.mamodels), &view);
+        assert_eq!(image.get_pixel(20, 20), &Rgba([255, 255, 255, 128]));
+    }
+
+    #[test]
+    fn load() {
+        let selector = UnitSelector::Enemy(4);
+        let mut png = std::io::Cursor::new(Vec::new());
+        texture().write_to(&mut png, ImageFormat::Png).unwrap();
+        let mut source = MemorySource::new();
+        source
+            .insert(
+                selector.mamodels(),
+                "[modelanim:model2]\n1\n2\n\
+                -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+                0,1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+                1000,3600,1000\n",
+            )
+            .insert(selector.imgcuts(), "[imgcut]\n0\n004_e.png\n1\n0,0,20,10\n")
+            .insert(selector.image(), png.get_ref().clone())
+            .insert(selector.sheet_imgcut(1), "[imgcut]\n0\n004_e_01.png\n1\n20,0,10,10\n");
+        // 2枚目の画像が無い
+        assert!(HeadlessUnit::load(&source, selector).is_err());
+
+        source.insert(selector.sheet_image(1), png.into_inner());
+        let unit = HeadlessUnit::load(&source, selector).unwrap();
+        assert_eq!(unit.textures.len(), 2);
+        assert_eq!(unit.image.sheets[1].texture_size, Size2d { width: 40, height: 20 });
+        let state = UnitState::from_model(&unit.image.mamodels);
+        let bounds = unit.bounds(&state).unwrap();
+        assert_eq!((bounds.width(), bounds.height()), (10., 10.));
+    }
+}
@@ -0,0 +1,255 @@
+//! パーツの親子関係を辿って、各パーツの最終的な変換を求める
+//!
+//! Bevyの親子関係(Parent/Children)は使わないので、どの描画方法からも使える。
+//! 座標はユニットの原点から見たもので、y軸は上向き。
+
+use bevy::math::{Affine3A, Quat, Vec3};
+
+use super::{sheet_index, UnitImage, UnitState};
+use crate::database::GlowType;
+
+/// 1パーツ分の結果
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartWorldTransform {
+    /// パーツの変換(子パーツにも伝わる)
+    pub transform: Affine3A,
+    /// 画像の四角形(-0.5〜0.5)を置く変換(pivotとextendを含む)
+    pub image: Affine3A,
+    /// 親の不透明度を掛けたもの(0〜1)
+    pub opacity: f32,
+    /// 親を辿って足したz
+    pub depth: f32,
+    /// 描く順(depthの小さい方から0、1、...)
+    pub order: usize,
+    pub glow: GlowType,
+    /// 実際に使うシートの番号(無いシートは0)
+    pub sheet: usize,
+    /// 画像の番号(ルートやimgcutに無い番号はNone)
+    pub img: Option<usize>,
+}
+
+/// 親パーツまでを合成した状態
+#[derive(Clone, Copy)]
+struct PartNode {
+    transform: Affine3A,
+    depth: f32,
+    opacity: f32,
+    /// scalex、scaleyの符号で向きが反転しているか
+    flipped: bool,
+}
+
+/// 無いシートは0番(UnitImage::sheetと同じ)
+fn sheet_of(image: &UnitImage, id: i32) -> usize {
+    let sheet = sheet_index(id);
+    if sheet < image.sheets.len() {
+        sheet
+    } else {
+        0
+    }
+}
+
+/// 親の番号(ルートや範囲外はNone)
+fn parent_of(states: &UnitState, i: usize) -> Option<usize> {
+    let parent = usize::try_from(states.states[i].parent).ok()?;
+    (i > 0 && parent < states.states.len() && parent != i).then_some(parent)
+}
+
+/// パーツの番号順に返す
+/// stateはmamodelを適用する前のもの(StateGenerator::current_stateなど)
+pub fn solve(state: &UnitState, image: &UnitImage) -> Vec<PartWorldTransform> {
+    let mut states = state.clone();
+    states.apply_model(&image.mamodels);
+    let len = states.states.len();
+    let opacity_ratio = (image.mamodels.opacity_ratio as f32).powi(2);
+    let scale_ratio = (image.mamodels.scale_ratio as f32).powi(3);
+    let angle_ratio = image.mamodels.angle_ratio as f32;
+
+    let node = |i: usize, parent: Option<&PartNode>| {
+        let state = &states.states[i];
+        let zorder = match parent_of(&states, i) {
+            Some(p) if parent.is_some() => state.zorder - states.states[p].zorder,
+            _ => state.zorder,
+        };
+        let flip = |flip: bool, v: f32| if flip { -v } else { v };
+        let scale = Vec3::new(
+            flip(state.horizontal_flip, state.scalex) * state.scale / scale_ratio,
+            flip(state.vertical_flip, state.scaley) * state.scale / scale_ratio,
+            1.,
+        );
+        let mut angle = -state.angle / angle_ratio * 2. * std::f32::consts::PI;
+        angle = flip(state.horizontal_flip ^ state.vertical_flip, angle);
+        // 親が反転していると回転の向きも逆になる
+        let parent_flipped = parent.is_some_and(|p| p.flipped);
+        angle = flip(parent_flipped, angle);
+        let z = zorder as f32 + i as f32 / len as f32;
+        let local = Affine3A::from_scale_rotation_translation(
+            scale,
+            Quat::from_rotation_z(angle),
+            Vec3::new(state.x, -state.y, z),
+        );
+        PartNode {
+            transform: parent.map_or(local, |p| p.transform * local),
+            depth: parent.map_or(0., |p| p.depth) + z,
+            opacity: parent.map_or(1., |p| p.opacity) * state.opacity / opacity_ratio,
+            flipped: (state.scalex > 0.) ^ (state.scaley > 0.) ^ parent_flipped,
+        }
+    };
+
+    // 親の方が番号が大きいこともあるので、決まっていない親を先に辿る
+    // 循環していたら辿り始めたパーツをルートとして扱う
+    let mut nodes: Vec<Option<PartNode>> = vec![None; len];
+    for i in 0..len {
+        let mut chain = vec![i];
+        while let Some(p) = parent_of(&states, *chain.last().unwrap()) {
+            if nodes[p].is_some() || chain.contains(&p) {
+                break;
+            }
+            chain.push(p);
+        }
+        for &j in chain.iter().rev() {
+            if nodes[j].is_none() {
+                let parent = parent_of(&states, j).and_then(|p| nodes[p]);
+                nodes[j] = Some(node(j, parent.as_ref()));
+            }
+        }
+    }
+
+    let mut parts: Vec<PartWorldTransform> = nodes
+        .into_iter()
+        .flatten()
+        .zip(&states.states)
+        .enumerate()
+        .map(|(i, (node, state))| {
+            let sheet = sheet_of(image, state.sheet);
+            // ルートは画像を持たない
+            let img = usize::try_from(state.img)
+                .ok()
+                .filter(|&img| i > 0 && img < image.sheets[sheet].imgcuts.len());
+            let size = image.size(sheet as i32, img.map_or(-1, |img| img as i32));
+            let (width, height) = (size.width as f32, size.height as f32);
+            // extendは画像だけをpivotを中心に引き伸ばす
+            let extendx = state.extendx / image.mamodels.scale_ratio as f32;
+            let extendy = state.extendy / image.mamodels.scale_ratio as f32;
+            let child = Affine3A::from_scale_rotation_translation(
+                Vec3::new(width * extendx, height * extendy, 1.),
+                Quat::IDENTITY,
+                Vec3::new(
+                    (width / 2. - state.pivotx) * extendx,
+                    (state.pivoty - height / 2.) * extendy,
+                    0.,
+                ),
+            );
+            PartWorldTransform {
+                transform: node.transform,
+                image: node.transform * child,
+                opacity: node.opacity,
+                depth: node.depth,
+                order: 0,
+                glow: state.glow,
+                sheet,
+                img,
+            }
+        })
+        .collect();
+
+    let mut order: Vec<usize> = (0..parts.len()).collect();
+    order.sort_by(|&a, &b| parts[a].depth.total_cmp(&parts[b].depth));
+    for (k, i) in order.into_iter().enumerate() {
+        parts[i].order = k;
+    }
+    parts
+}
+
+/// 描く順に並べたパーツの番号
+pub fn draw_order(parts: &[PartWorldTransform]) -> Vec<usize> {
+    let mut order = vec![0; parts.len()];
+    for (i, part) in parts.iter().enumerate() {
+        order[part.order] = i;
+    }
+    order
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::database::{
+        animation::{Size2d, UnitSheet},
+        Imgcut, Mamodels,
+    };
+    use bevy::{math::Vec2, sprite::Mesh2dHandle};
+
+    pub(crate) fn image(mamodel: &str) -> UnitImage {
+        let (_, imgcuts) = Imgcut::from_str("[imgcut]\n0\na.png\n2\n0,0,20,10\n20,0,10,10\n").unwrap();
+        UnitImage {
+            materials: Vec::new(),
+            sheets: vec![UnitSheet {
+                size: imgcuts.iter().cloned().map(Size2d::from).collect(),
+                meshes: vec![Mesh2dHandle::default(); imgcuts.len()],
+                imgcuts,
+                texture: Default::default(),
+                texture_size: Size2d {
+                    width: 40,
+                    height: 20,
+                },
+            }],
+            mamodels: Mamodels::from_reader(mamodel.as_bytes()).unwrap(),
+        }
+    }
+
+    fn point(transform: &Affine3A, x: f32, y: f32) -> Vec2 {
+        transform.transform_point3(Vec3::new(x, y, 0.)).truncate()
+    }
+
+    fn assert_near(a: Vec2, b: Vec2) {
+        assert!(a.abs_diff_eq(b, 1e-3), "{a} != {b}");
+    }
+
+    #[test]
+    fn hierarchy() {
+        // 1: 上下反転して(100,50)に置く
+        // 2: 1の子で、90度回して(10,0)に置く、後ろに描く
+        // 3: 2の子で、半透明
+        let image = image(
+            "[modelanim:model2]\n1\n4\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,2,100,50,0,0,1000,-1000,0,500,0\n\
+            1,0,1,0,10,0,0,0,1000,1000,900,1000,2\n\
+            2,0,0,3,0,10,0,0,1000,1000,0,500,0\n\
+            1000,3600,1000\n",
+        );
+        let parts = solve(&UnitState::from_model(&image.mamodels), &image);
+        assert_eq!(parts.len(), 4);
+        assert_eq!(parts[0].img, None);
+        assert_eq!(parts.iter().map(|p| p.opacity).collect::<Vec<_>>(), [1., 0.5, 0.5, 0.25]);
+        assert_eq!((parts[2].glow, parts[2].img), (GlowType::White, Some(1)));
+        assert_eq!(draw_order(&parts), [0, 2, 1, 3]);
+
+        assert_near(point(&parts[1].transform, 0., 0.), Vec2::new(100., -50.));
+        // 上下反転しているので、親の(10,0)はそのまま、子の回転は逆向き(反時計回り)になる
+        assert_near(point(&parts[2].transform, 0., 0.), Vec2::new(110., -50.));
+        // 3は2から見て(0,-10)で、2の回転と1の反転を受ける
+        assert_near(point(&parts[3].transform, 0., 0.), Vec2::new(120., -50.));
+        // 画像は20x10でpivotは(0,0)、上下反転しているので右下の角が上に来る
+        assert_near(point(&parts[1].image, 0.5, -0.5), Vec2::new(120., -40.));
+    }
+
+    #[test]
+    fn broken_parents() {
+        // 親の方が後ろの番号、範囲外、循環していてもよい
+        let image = image(
+            "[modelanim:model2]\n1\n5\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            2,0,0,1,10,0,0,0,1000,1000,0,1000,0\n\
+            7,0,0,0,0,20,0,0,1000,1000,0,1000,0\n\
+            4,0,0,0,5,0,0,0,1000,1000,0,1000,0\n\
+            3,0,0,0,5,0,0,0,1000,1000,0,1000,0\n\
+            1000,3600,1000\n",
+        );
+        let parts = solve(&UnitState::from_model(&image.mamodels), &image);
+        assert_near(point(&parts[2].transform, 0., 0.), Vec2::new(0., -20.));
+        assert_near(point(&parts[1].transform, 0., 0.), Vec2::new(10., -20.));
+        assert!((parts[1].depth - parts[2].depth - 1.2).abs() < 1e-6);
+        let cycle = [&parts[3], &parts[4]].map(|p| point(&p.transform, 0., 0.));
+        assert!(cycle.contains(&Vec2::new(5., 0.)) && cycle.contains(&Vec2::new(10., 0.)));
+    }
+}
@@ -1,36 +1,43 @@
 #![allow(dead_code)]
 
 use crate::database::{
-    consume_buf, get_next, get_next_line, get_string, Mamodel, Mamodels, ASSET_PATH,
+    consume_buf, get_next, get_next_line, get_string, source::AssetSource, LineReader, Mamodel,
+    Mamodels,
 };
-use bevy::prelude::*;
+use bevy::{prelude::*, reflect::TypeUuid};
 use serde::{Deserialize, Serialize};
 
 use super::super::error::{Error, ErrorKind};
 use super::UnitState;
 use std::cmp::Ordering;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, Lines};
+use std::io::{self, BufRead, BufReader, Lines, Write};
 use std::path::Path;
 use std::str::{FromStr, Split};
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, TypeUuid)]
+#[uuid = "739ded46-465f-40f8-a202-08b9d364443c"]
 pub struct Maanim {
+    /// 先頭の2行(`[modelanim:animation2]`など、そのまま書き戻す)
+    header: [String; 2],
     parts: Vec<MaanimPart>,
     period: u32,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct MaanimPart {
     id: u16,
     modification: Modification,
-    loops: bool,
+    /// ファイルに書かれているループ回数(-1で無限)
+    loop_count: i32,
+    /// ループ回数以降の列(そのまま書き戻す)
+    extra: Vec<String>,
     eases: Vec<Ease>,
     frame_start: i32,
     frame_end: i32,
 }
 
-#[derive(Debug, Default, Clone)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Ease {
     frame: i32,
     value: i32,
@@ -108,6 +115,50 @@ impl TryFrom<i32> for Modification {
     }
 }
 
+impl From<Modification> for i32 {
+    fn from(value: Modification) -> Self {
+        match value {
+            Modification::Parent => 0,
+            Modification::Id => 1,
+            Modification::Sprite => 2,
+            Modification::Zorder => 3,
+            Modification::Xpos => 4,
+            Modification::Ypos => 5,
+            Modification::Pivotx => 6,
+            Modification::Pivoty => 7,
+            Modification::Scale => 8,
+            Modification::Scalex => 9,
+            Modification::Scaley => 10,
+            Modification::Angle => 11,
+            Modification::Opacity => 12,
+            Modification::HorizontalFlip => 13,
+            Modification::VerticalFlip => 14,
+            Modification::ExtendX => 50,
+            Modification::ExtendY => 52,
+        }
+    }
+}
+
+impl Modification {
+    /// フレームの間で値を補間できるか(親、画像、zorder、反転などはできない)
+    pub fn interpolates(self) -> bool {
+        matches!(
+            self,
+            Modification::Xpos
+                | Modification::Ypos
+                | Modification::Pivotx
+                | Modification::Pivoty
+                | Modification::Scale
+                | Modification::Scalex
+                | Modification::Scaley
+                | Modification::Angle
+                | Modification::Opacity
+                | Modification::ExtendX
+                | Modification::ExtendY
+        )
+    }
+}
+
 impl Sign {
     fn from_int(num: i32) -> Self {
         match num {
@@ -116,6 +167,59 @@ impl Sign {
             1.. => Positive,
         }
     }
+
+    fn to_int(self) -> i32 {
+        match self {
+            Negative => -1,
+            Zero => 0,
+            Positive => 1,
+        }
+    }
+}
+
+impl Easing {
+    /// (イージングの種類, パラメータ)
+    fn to_ints(self) -> (i32, i32) {
+        match self {
+            Easing::Linear => (0, 0),
+            Easing::Nothing => (1, 0),
+            Easing::InOut(p) => (2, p),
+            Easing::Ease3 => (3, 0),
+            Easing::Sine(sig) => (4, sig.to_int()),
+        }
+    }
+}
+
+impl MaanimPart {
+    pub fn loops(&self) -> bool {
+        self.loop_count != -1
+    }
+
+    pub fn id(&self) -> u16 {
+        self.id
+    }
+
+    pub fn modification(&self) -> Modification {
+        self.modification
+    }
+
+    pub fn eases(&self) -> &[Ease] {
+        &self.eases
+    }
+}
+
+impl Ease {
+    pub fn frame(&self) -> i32 {
+        self.frame
+    }
+
+    pub fn value(&self) -> i32 {
+        self.value
+    }
+
+    pub fn easing(&self) -> Easing {
+        self.easing
+    }
 }
 
 /// [(id0, diff0), (id1, diff1), (id2, diff2), ...]
@@ -175,48 +279,67 @@ impl StateDiffVal {
 }
 
 impl Maanim {
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        let f = File::open(Path::new(ASSET_PATH).join(path))?;
-        let reader = BufReader::new(f);
-        let mut lines = reader.lines();
-
-        consume_buf(&mut lines, |s| {
-            s.starts_with("[modelanim:animation2]") || s.starts_with("[modelanim:animation]")
-        })?;
-        consume_buf(&mut lines, |_| true)?;
-        let len = get_next_line(&mut lines)?;
+    pub fn parts(&self) -> &[MaanimPart] {
+        &self.parts
+    }
+
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    pub fn load<P: AsRef<Path>>(source: &dyn AssetSource, path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        source
+            .read(path)
+            .and_then(|bytes| Self::from_reader(bytes.as_slice()))
+            .map_err(|err| err.with_path(path))
+    }
+
+    pub fn from_reader<R: BufRead>(reader: R) -> Result<Self, Error> {
+        let mut lines = LineReader::new(reader, "maanim");
+
+        let header = [
+            consume_buf(&mut lines, |s| {
+                s.starts_with("[modelanim:animation2]") || s.starts_with("[modelanim:animation]")
+            })?,
+            get_string(&mut lines)?,
+        ];
+        let len = get_next_line(&mut lines, "length")?;
         let mut parts = Vec::with_capacity(len);
         let mut period = 0;
         for _ in 0..len {
             let s = get_string(&mut lines)?;
-            let mut split = s.split(',');
+            let mut split = lines.fields(&s);
 
-            let part_id = get_next(&mut split)?;
-            let modification: i32 = get_next(&mut split)?;
-            let loops: i32 = get_next(&mut split)?;
+            let part_id = get_next(&mut split, "id")?;
+            let modification: Modification = get_next::<i32>(&mut split, "modification")?
+                .try_into()
+                .map_err(|err| split.locate(err))?;
+            let loop_count: i32 = get_next(&mut split, "loop")?;
+            let extra = split.rest();
 
-            let len = get_next_line(&mut lines)?;
+            let len = get_next_line(&mut lines, "length")?;
 
             let mut eases = Vec::with_capacity(len);
 
             for _ in 0..len {
                 let s = get_string(&mut lines)?;
-                let mut split = s.split(',');
+                let mut split = lines.fields(&s);
                 eases.push(Ease {
-                    frame: get_next(&mut split)?,
-                    value: get_next(&mut split)?,
+                    frame: get_next(&mut split, "frame")?,
+                    value: get_next(&mut split, "value")?,
                     easing: {
-                        match get_next::<i32>(&mut split)? {
+                        match get_next::<i32>(&mut split, "easing")? {
                             0 => Easing::Linear,
                             1 => Easing::Nothing,
-                            2 => Easing::InOut(get_next(&mut split)?),
+                            2 => Easing::InOut(get_next(&mut split, "param")?),
                             3 => Easing::Ease3,
-                            4 => Easing::Sine(Sign::from_int(get_next(&mut split)?)),
+                            4 => Easing::Sine(Sign::from_int(get_next(&mut split, "param")?)),
                             _ => {
-                                return Err(Error::new(
+                                return Err(split.locate(Error::new(
                                     ErrorKind::InvalidNumber,
                                     "無効なEasingタイプ",
-                                ));
+                                )));
                             }
                         }
                     },
@@ -230,8 +353,9 @@ impl Maanim {
                 .unwrap_or_default();
             parts.push(MaanimPart {
                 id: part_id,
-                modification: modification.try_into()?,
-                loops: loops != -1,
+                modification,
+                loop_count,
+                extra,
                 eases,
                 frame_start,
                 frame_end,
@@ -241,9 +365,36 @@ impl Maanim {
                 period = range;
             }
         }
-        parts.sort_by(|a, b| a.id.cmp(&b.id));
+        parts.sort_by_key(|part| part.id);
         // println!("period: {period}");
-        Ok(Maanim { parts, period })
+        Ok(Maanim { header, parts, period })
+    }
+
+    /// 読み込んだときと同じ形式で書き出す
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        for s in &self.header {
+            writeln!(writer, "{s}")?;
+        }
+        writeln!(writer, "{}", self.parts.len())?;
+        for part in &self.parts {
+            write!(
+                writer,
+                "{},{},{}",
+                part.id,
+                i32::from(part.modification),
+                part.loop_count
+            )?;
+            for s in &part.extra {
+                write!(writer, ",{s}")?;
+            }
+            writeln!(writer)?;
+            writeln!(writer, "{}", part.eases.len())?;
+            for ease in &part.eases {
+                let (easing, param) = ease.easing.to_ints();
+                writeln!(writer, "{},{},{easing},{param}", ease.frame, ease.value)?;
+            }
+        }
+        Ok(())
     }
 
     pub fn into_state_generator(self, mamodels: &Mamodels) -> StateGenerator {
@@ -271,6 +422,14 @@ impl Maanim {
     }
 }
 
+impl FromStr for Maanim {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::from_reader(s.as_bytes())
+    }
+}
+
 use std::collections::VecDeque;
 #[derive(Clone, Debug, Default)]
 pub struct StateDiffGenerator {
@@ -331,7 +490,7 @@ enum DiffOrNothing {
     Nothing(u16),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DiffData {
     id: u16,
     border: u32,
@@ -341,8 +500,12 @@ pub struct DiffData {
     data: Box<[i32]>,
 }
 
-#[derive(Clone, Debug, Default, Serialize, Deserialize)]
-pub struct StateDiffData(Box<[DiffData]>);
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StateDiffData {
+    /// 元のmaanimの周期(Maanim::period)
+    period: u32,
+    parts: Box<[DiffData]>,
+}
 
 impl StateDiffData {
     // pub fn from_generator(mut generator: StateDiffGenerator, model: &Mamodels) -> Self {
@@ -370,7 +533,8 @@ impl StateDiffData {
     // }
 
     pub fn from_anim(maanim: Maanim, models: &Mamodels) -> Self {
-        Self(maanim.parts.into_iter().filter_map(|part| {
+        let period = maanim.period as usize;
+        let parts = maanim.parts.into_iter().filter_map(|part| {
             match part.eases.len() {
                 0 => None,
                 1 => Some(DiffData {
@@ -396,65 +560,13 @@ impl StateDiffData {
                         partial_loop = false;
                         border = (-frame) as u32;
                     }
-                    if part.loops {
+                    if part.loops() && frame > 0 {
                         partial_loop = true;
                         border = u32::MAX;
                     }
                     let eases = &part.eases;
                     while ind < eases.len() - 1 {
                         match eases[ind].easing {
-                            Easing::Linear => {
-                                let ease1 = &eases[ind];
-                                ind += 1;
-                                let ease2 = &eases[ind];
-                                let fd = ease2.frame - ease1.frame;
-                                let vd = ease2.value - ease1.value;
-                                v.extend((0..fd).map(|i| ease1.value + (i as f64 / fd as f64 * vd as f64) as i32));
-                            },
-                            Easing::Nothing => {
-                                let ease1 = &eases[ind];
-                                ind += 1;
-                                let ease2 = &eases[ind];
-                                let fd = ease2.frame - ease1.frame;
-                                v.extend((0..fd).map(|i| ease1.value));
-                            },
-                            Easing::InOut(p) => {
-                                fn neg(x: f64, param: i32) -> f64 {
-                                    (1. - (1. - x).powi(-param)).sqrt()
-                                }
-                                fn pos(x: f64, param: i32) -> f64 {
-                                    1. - (1. - x.powi(param)).sqrt()
-                                }
-                                let func = if p >= 0 { pos } else { neg };
-                                let ease1 = &eases[ind];
-                                ind += 1;
-                                let ease2 = &eases[ind];
-                                let fd = ease2.frame - ease1.frame;
-                                let vd = ease2.value - ease1.value;
-                                v.extend((0..fd).map(|i| ease1.value + (vd as f64 * func(i as f64 / fd as f64, p)) as i32));
-                            },
-                            Easing::Sine(sig) => {
-                                fn sine_p(x: f64) -> f64 {
-                                    1. - (x * std::f64::consts::FRAC_PI_2).cos()
-                                }
-                                fn sine_n(x: f64) -> f64 {
-                                    (x * std::f64::consts::FRAC_PI_2).sin()
-                                }
-                                fn sine_z(x: f64) -> f64 {
-                                    (1. - (x * std::f64::consts::PI).cos()) / 2.
-                                }
-                                let func = match sig {
-                                    Sign::Positive => sine_p,
-                                    Sign::Negative => sine_n,
-                                    Sign::Zero => sine_z,
-                                };
-                                let ease1 = &eases[ind];
-                                ind += 1;
-                                let ease2 = &eases[ind];
-                                let fd = ease2.frame - ease1.frame;
-                                let vd = ease2.value - ease1.value;
-                                v.extend((0..fd).map(|i| ease1.value + (vd as f64 * func(i as f64 / fd as f64)) as i32));
-                            },
                             Easing::Ease3 => {
                                 let low = ind;
                                 ind += 1;
@@ -467,9 +579,19 @@ impl StateDiffData {
                                 let high = ind;
                                 v.extend(calculate_ease3(&eases[low..=high]));
                             }
+                            _ => {
+                                let ease1 = &eases[ind];
+                                ind += 1;
+                                let ease2 = &eases[ind];
+                                v.extend((ease1.frame..ease2.frame).map(|f| ease_value(ease1, ease2, f)));
+                            }
                         }
                     }
                     v.push(eases.last().unwrap().value);
+                    if part.loops() && frame <= 0 {
+                        // アニメーション全体の周期でループし、最後のキーフレームの後はその値のまま
+                        v.resize(period + 1, eases.last().unwrap().value);
+                    }
                     Some(DiffData {
                         id: part.id,
                         border,
@@ -479,83 +601,306 @@ impl StateDiffData {
                     })
                 }
             }
-        }).collect())
+        }).collect();
+        Self {
+            period: maanim.period,
+            parts,
+        }
     }
 
     pub fn apply_state(&self, states: &mut UnitState, frame: u32) {
-        for data in self.0.as_ref() {
-            let border = data.border as usize;
-            let len = data.data.len() - 1;
-            let ind = if data.partial_loop {
-                if frame < data.border {
-                    frame as usize
-                } else {
-                    (frame as usize - border).checked_rem(len - border).unwrap_or(0) + border
-                    // (frame as usize - border) % (data.data.len() - border) + border
-                }
-            } else {
-                (frame as usize + border) % len
-            };
-            if let Some(val) = data.data.get(ind).copied() {
+        for data in self.parts.as_ref() {
+            if let Some(val) = data.data.get(data.index(frame)).copied() {
                 states.states[data.id as usize].load_diff(StateDiffVal::new(data.modification, val));
             }
         }
     }
+
+    /// 元のmaanimの周期(書き出しと同じ長さでシークや逆再生を折り返す)
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    /// 途中のフレーム(frame = 1.5など)の状態を計算する
+    /// 整数のフレームではapply_stateを0から順に呼んだものと同じになる
+    pub fn apply_state_at(&self, states: &mut UnitState, frame: f32) {
+        let frame = frame.max(0.);
+        let fract = frame.fract();
+        for data in self.parts.as_ref() {
+            let last = data.data.len() - 1;
+            // 範囲外のフレームは最後の値のまま
+            let ind = data.index(frame as u32).min(last);
+            let val = data.data[ind];
+            let state = &mut states.states[data.id as usize];
+            if fract > 0. && data.modification.interpolates() {
+                let next = data.data[(ind + 1).min(last)];
+                state.load_value(data.modification, val as f32 + (next - val) as f32 * fract);
+            } else {
+                state.load_diff(StateDiffVal::new(data.modification, val));
+            }
+        }
+    }
+}
+
+impl DiffData {
+    /// frameで使うdataの添字
+    /// 範囲外になることがあり、その場合は前のフレームの値のまま
+    fn index(&self, frame: u32) -> usize {
+        let border = self.border as usize;
+        let len = self.data.len() - 1;
+        if self.partial_loop {
+            if frame < self.border {
+                frame as usize
+            } else {
+                (frame as usize - border).checked_rem(len - border).unwrap_or(0) + border
+                // (frame as usize - border) % (data.data.len() - border) + border
+            }
+        } else {
+            (frame as usize + border) % len
+        }
+    }
 }
 
 
+pub mod cache;
+pub mod evaluator;
+
 pub mod from_data {
-    use bevy::prelude::Resource;
+    use bevy::prelude::Component;
 
     use crate::database::{animation::UnitState, Mamodels};
     use super::{StateDiffData, Maanim};
 
-    #[derive(Resource, Clone, Debug)]
+    /// maanimのフレームレート
+    pub const FPS: f32 = 30.;
+
+    /// 前のアニメーションの最後の状態から切り替わっていく途中
+    #[derive(Clone, Debug)]
+    struct Crossfade {
+        from: UnitState,
+        /// 秒
+        elapsed: f32,
+        duration: f32,
+    }
+
+    /// ユニットごとにUnitのエンティティに付ける
+    #[derive(Component, Clone, Debug)]
     pub struct StateGenerator {
         data: StateDiffData,
         current_frame: u32,
         current_state: UnitState,
+        /// アニメーションを適用する前の状態
+        base_state: UnitState,
+        /// advanceで進めた時間(フレーム単位)
+        time: f32,
+        fade: Option<Crossfade>,
+        playing: bool,
+        /// 再生速度の倍率
+        speed: f32,
+        reverse: bool,
     }
 
     impl StateGenerator {
         pub fn from_anim(maanim: Maanim, models: &Mamodels) -> Self {
+            Self::from_data(StateDiffData::from_anim(maanim, models), models)
+        }
+        /// 計算済みのデータ(キャッシュなど)から作る
+        pub fn from_data(data: StateDiffData, models: &Mamodels) -> Self {
+            let state = UnitState::from_model(models);
             Self {
-                data: StateDiffData::from_anim(maanim, models),
+                data,
                 current_frame: 0,
-                current_state: UnitState::from_model(models),
+                current_state: state.clone(),
+                base_state: state,
+                time: 0.,
+                fade: None,
+                playing: true,
+                speed: 1.,
+                reverse: false,
+            }
+        }
+
+        /// アニメーションを差し替えて先頭から再生する
+        /// 一時停止、再生速度、逆再生はそのまま
+        pub fn set_data(&mut self, data: StateDiffData) {
+            self.data = data;
+            self.current_frame = 0;
+            self.current_state = self.base_state.clone();
+            self.time = 0.;
+            self.fade = None;
+        }
+
+        /// set_dataと同じだが、今の状態からduration秒かけて新しいアニメーションに切り替える
+        pub fn crossfade(&mut self, data: StateDiffData, duration: f32) {
+            let from = self.current_state();
+            self.set_data(data);
+            if duration > 0. {
+                self.fade = Some(Crossfade {
+                    from,
+                    elapsed: 0.,
+                    duration,
+                });
             }
         }
+
+        pub fn is_fading(&self) -> bool {
+            self.fade.is_some()
+        }
+
         pub fn next_state(&mut self) -> UnitState {
-            let Self { data, current_frame, current_state } = self;
+            let Self { data, current_frame, current_state, .. } = self;
             data.apply_state(current_state, *current_frame);
             *current_frame += 1;
             current_state.clone()
         }
 
-        pub fn empty(models: &Mamodels) -> Self {
-            Self {
-                data: StateDiffData::default(),
-                current_frame: 0,
-                current_state: UnitState::from_model(models),
+        /// frame(1/30秒単位、小数も可)の時点の状態
+        pub fn state_at(&self, frame: f32) -> UnitState {
+            let mut state = self.base_state.clone();
+            self.data.apply_state_at(&mut state, frame);
+            state
+        }
+
+        /// 再生中ならdt秒(に再生速度を掛けた分)進めて、その時点の状態を返す
+        /// 切り替えの途中なら一時停止中でも切り替えは進める
+        pub fn advance(&mut self, dt: f32) -> UnitState {
+            if let Some(fade) = &mut self.fade {
+                fade.elapsed += dt;
+                if fade.elapsed >= fade.duration {
+                    self.fade = None;
+                }
+            }
+            if self.playing {
+                let delta = dt * FPS * self.speed;
+                self.seek(if self.reverse {
+                    self.time - delta
+                } else {
+                    self.time + delta
+                });
             }
+            self.current_state()
+        }
+
+        /// 切り替えの途中なら前のアニメーションと混ぜた状態
+        pub fn current_state(&self) -> UnitState {
+            let state = self.state_at(self.time);
+            match &self.fade {
+                Some(fade) => fade.from.blend(&state, fade.elapsed / fade.duration),
+                None => state,
+            }
+        }
+
+        pub fn time(&self) -> f32 {
+            self.time
+        }
+
+        pub fn period(&self) -> u32 {
+            self.data.period()
+        }
+
+        /// 0より前はperiodで折り返す(periodが0なら0で止める)
+        pub fn seek(&mut self, frame: f32) {
+            let period = self.period();
+            self.time = if frame >= 0. {
+                frame
+            } else if period > 0 {
+                frame.rem_euclid(period as f32)
+            } else {
+                0.
+            };
         }
+
+        /// 一時停止して次の整数フレームに進める
+        pub fn step_forward(&mut self) {
+            self.playing = false;
+            self.seek(self.time.floor() + 1.);
+        }
+
+        /// 一時停止して前の整数フレームに戻す
+        pub fn step_backward(&mut self) {
+            self.playing = false;
+            self.seek(self.time.ceil() - 1.);
+        }
+
+        pub fn play(&mut self) {
+            self.playing = true;
+        }
+
+        pub fn pause(&mut self) {
+            self.playing = false;
+        }
+
+        pub fn toggle_pause(&mut self) {
+            self.playing = !self.playing;
+        }
+
+        pub fn is_playing(&self) -> bool {
+            self.playing
+        }
+
+        pub fn speed(&self) -> f32 {
+            self.speed
+        }
+
+        /// 負の値は0にする(逆再生はset_reverseで指定する)
+        pub fn set_speed(&mut self, speed: f32) {
+            self.speed = speed.max(0.);
+        }
+
+        pub fn is_reverse(&self) -> bool {
+            self.reverse
+        }
+
+        pub fn set_reverse(&mut self, reverse: bool) {
+            self.reverse = reverse;
+        }
+
+        pub fn empty(models: &Mamodels) -> Self {
+            Self::from_data(StateDiffData::default(), models)
+        }
+    }
+}
+/// 2つのキーフレームの間の進み具合x(0..=1)をイージングに従って変換する
+/// Ease3は3つ以上のキーフレームを使うのでease3_valueで計算する
+pub fn ease_curve(easing: Easing, x: f64) -> f64 {
+    use std::f64::consts::{FRAC_PI_2, PI};
+    match easing {
+        Easing::Linear | Easing::Ease3 => x,
+        Easing::Nothing => 0.,
+        Easing::InOut(p) if p >= 0 => 1. - (1. - x.powi(p)).sqrt(),
+        Easing::InOut(p) => (1. - (1. - x).powi(-p)).sqrt(),
+        Easing::Sine(Sign::Positive) => 1. - (x * FRAC_PI_2).cos(),
+        Easing::Sine(Sign::Negative) => (x * FRAC_PI_2).sin(),
+        Easing::Sine(Sign::Zero) => (1. - (x * PI).cos()) / 2.,
     }
 }
+
+/// ease1.frame <= frame < ease2.frameのフレームの値(ease1のイージングを使う)
+pub fn ease_value(ease1: &Ease, ease2: &Ease, frame: i32) -> i32 {
+    let fd = ease2.frame - ease1.frame;
+    let vd = ease2.value - ease1.value;
+    let x = (frame - ease1.frame) as f64 / fd as f64;
+    ease1.value + (vd as f64 * ease_curve(ease1.easing, x)) as i32
+}
+
+/// Ease3が続くキーフレーム全体を通るラグランジュ補間
+pub fn ease3_value(eases: &[Ease], frame: i32) -> i32 {
+    (eases.iter().enumerate().map(|(i, ease)| {
+        let mut val = 4096. * ease.value as f64;
+        eases.get(..i).into_iter().for_each(|eases| eases.iter().for_each(|ease2| {
+            val *= (frame - ease2.frame) as f64 / (ease.frame - ease2.frame) as f64;
+        }));
+        eases.get((i + 1)..).into_iter().for_each(|eases| eases.iter().for_each(|ease2| {
+            val *= (frame - ease2.frame) as f64 / (ease.frame - ease2.frame) as f64;
+        }));
+        val
+    }).sum::<f64>() / 4096.) as i32
+}
+
 fn calculate_ease3(eases: &[Ease]) -> impl Iterator<Item = i32> + '_ {
     let low = eases.first().unwrap().frame;
     let high = eases.last().unwrap().frame;
-    (low..high).map(|f| {
-        (eases.iter().enumerate().map(|(i, ease)| {
-            let mut val = 4096. * ease.value as f64;
-            eases.get(..i).into_iter().for_each(|eases| eases.iter().for_each(|ease2| {
-                val *= (f - ease2.frame) as f64 / (ease.frame - ease2.frame) as f64;
-            }));
-            eases.get((i + 1)..).into_iter().for_each(|eases| eases.iter().for_each(|ease2| {
-                val *= (f - ease2.frame) as f64 / (ease.frame - ease2.frame) as f64;
-            }));
-            val
-        }).sum::<f64>() / 4096.) as i32
-    })
+    (low..high).map(|f| ease3_value(eases, f))
 }
 impl StateDiffGenerator {
     fn next_state_diff(&mut self) -> StateDiffs {
@@ -583,12 +928,12 @@ impl StateDiffGenerator {
                 }
                 _ => {}
             }
-            let period = if part.loops {
+            let period = if part.loops() {
                 maanim.period as i32
             } else {
                 part.frame_end - part.frame_start
             };
-            // println!("{}", part.loops);
+            // println!("{}", part.loops());
             let part_frame =
                 (*current_frame - part.frame_start).rem_euclid(period) + part.frame_start;
 
@@ -629,23 +974,15 @@ impl StateDiffGenerator {
 
                 let mut ease1 = &part.eases[*ind as usize];
                 *ind += 1;
-                let fd;
-                let vd;
-                let mut easing = ease1.easing;
-
-                // println!("{f}");
-                if let Some(ease2) = part.eases.get(*ind as usize) {
-                    fd = ease2.frame - ease1.frame;
-                    vd = ease2.value - ease1.value;
-                } else {
-                    ease1 = &part.eases[0];
-                    easing = ease1.easing;
-                    let ease2 = &part.eases[1];
-                    *ind = 1;
-                    fd = ease2.frame - ease1.frame;
-                    vd = ease2.value - ease1.value;
-                    // println!("else");
-                }
+                let ease2 = match part.eases.get(*ind as usize) {
+                    Some(ease2) => ease2,
+                    None => {
+                        ease1 = &part.eases[0];
+                        *ind = 1;
+                        &part.eases[1]
+                    }
+                };
+                let fd = ease2.frame - ease1.frame;
                 let f = part_frame - ease1.frame;
 
                 if !(ease1.frame..(ease1.frame + fd)).contains(&part_frame) {
@@ -655,23 +992,7 @@ impl StateDiffGenerator {
                         ease1.frame, part.id, part.frame_start,
                     );
                 }
-                match easing {
-                    Easing::Linear => {
-                        let l = vd as f64 / fd as f64;
-                        diff_set.push(StateDiff {
-                            id: part.id as _,
-                            diff: StateDiffVal::new(
-                                part.modification,
-                                ease1.value + (l * f as f64) as i32,
-                            ),
-                        });
-                        for i in (f + 1)..fd {
-                            queue.push_front(DiffOrNothing::Diff(StateDiffVal::new(
-                                part.modification,
-                                ease1.value + (l * i as f64) as i32,
-                            )));
-                        }
-                    }
+                match ease1.easing {
                     Easing::Nothing => {
                         diff_set.push(StateDiff {
                             id: part.id as _,
@@ -682,72 +1003,6 @@ impl StateDiffGenerator {
                             queue.push_front(DiffOrNothing::Nothing((fd - 1) as u16));
                         }
                     }
-                    Easing::InOut(p) => {
-                        if p >= 0 {
-                            let func = |x: f64| 1. - (1. - x.powi(p)).sqrt();
-                            diff_set.push(StateDiff {
-                                id: part.id as _,
-                                diff: StateDiffVal::new(
-                                    part.modification,
-                                    ease1.value + (vd as f64 * func(f as f64 / fd as f64)) as i32,
-                                ),
-                            });
-                            for i in (f + 1)..fd {
-                                queue.push_front(DiffOrNothing::Diff(StateDiffVal::new(
-                                    part.modification,
-                                    ease1.value + (vd as f64 * func(i as f64 / fd as f64)) as i32,
-                                )));
-                            }
-                        } else {
-                            let func = |x: f64| (1. - (1. - x).powi(-p)).sqrt();
-                            diff_set.push(StateDiff {
-                                id: part.id as _,
-                                diff: StateDiffVal::new(
-                                    part.modification,
-                                    ease1.value + (vd as f64 * func(f as f64 / fd as f64)) as i32,
-                                ),
-                            });
-                            for i in (f + 1)..fd {
-                                queue.push_front(DiffOrNothing::Diff(StateDiffVal::new(
-                                    part.modification,
-                                    ease1.value + (vd as f64 * func(i as f64 / fd as f64)) as i32,
-                                )));
-                            }
-                        };
-                    }
-                    Easing::Sine(sig) => {
-                        fn sine_p(x: f64) -> f64 {
-                            1. - (x * std::f64::consts::FRAC_PI_2).cos()
-                        }
-                        fn sine_n(x: f64) -> f64 {
-                            (x * std::f64::consts::FRAC_PI_2).sin()
-                        }
-                        fn sine_z(x: f64) -> f64 {
-                            (1. - (x * std::f64::consts::PI).cos()) / 2.
-                        }
-                        let func = match sig {
-                            Sign::Positive => sine_p,
-                            Sign::Negative => sine_n,
-                            Sign::Zero => sine_z,
-                        };
-
-                        diff_set.push(StateDiff {
-                            id: part.id as _,
-                            diff: StateDiffVal::new(
-                                part.modification,
-                                ease1.value + (vd as f64 * func(f as f64 / fd as f64)) as i32,
-                            ),
-                        });
-                        for i in (f + 1)..fd {
-                            queue.push_front(DiffOrNothing::Diff(StateDiffVal::new(
-                                part.modification,
-                                ease1.value + (vd as f64 * func(i as f64 / fd as f64)) as i32,
-                            )));
-                        }
-
-                        // println!("(id: {}, frame: {current_frame}): {queue:?}", part.id);
-                        // println!("func(): {}", func(1. / fd as f64));
-                    }
                     Easing::Ease3 => {
                         let low = (*ind - 1) as usize;
                         while *ind < (part.eases.len() - 1) as u16 {
@@ -757,41 +1012,12 @@ impl StateDiffGenerator {
                             *ind += 1;
                         }
                         let high = *ind as usize;
-                        let factors: Vec<f64> = part.eases[..=high]
-                            .iter()
-                            .enumerate()
-                            .skip(low)
-                            .map(|(i, e)| {
-                                let mut factor = 4096.0;
-                                for j in low..i {
-                                    factor /= (e.frame - part.eases[j].frame) as f64;
-                                }
-                                for j in (i + 1)..=high {
-                                    factor /= (e.frame - part.eases[j].frame) as f64;
-                                }
-                                factor
-                            })
-                            .collect();
-                        // println!("eases: {:?}", &part.eases[low..=high]);
-                        // println!("{factors:?}");
-                        // let frame = (*current_frame - part.frame_start) % (part.frame_end - part.frame_start) + part.frame_start;
+                        let eases = &part.eases[low..=high];
                         *queue = (part_frame..part.eases[high].frame)
                             .map(|f| {
-                                let mut sum = 0.;
-                                for (i, factor) in factors.iter().enumerate() {
-                                    let ind_i = i + low;
-                                    let mut val = *factor * part.eases[ind_i].value as f64;
-                                    for j in low..ind_i {
-                                        val *= (f - part.eases[j].frame) as f64;
-                                    }
-                                    for j in (ind_i + 1)..=high {
-                                        val *= (f - part.eases[j].frame) as f64;
-                                    }
-                                    sum += val;
-                                }
                                 DiffOrNothing::Diff(StateDiffVal::new(
                                     part.modification,
-                                    (sum / 4096.) as i32,
+                                    ease3_value(eases, f),
                                 ))
                             })
                             .rev()
@@ -804,6 +1030,21 @@ impl StateDiffGenerator {
                             });
                         }
                     }
+                    _ => {
+                        diff_set.push(StateDiff {
+                            id: part.id as _,
+                            diff: StateDiffVal::new(
+                                part.modification,
+                                ease_value(ease1, ease2, part_frame),
+                            ),
+                        });
+                        for frame in (part_frame + 1)..ease2.frame {
+                            queue.push_front(DiffOrNothing::Diff(StateDiffVal::new(
+                                part.modification,
+                                ease_value(ease1, ease2, frame),
+                            )));
+                        }
+                    }
                 }
             }
         }
@@ -816,7 +1057,8 @@ impl StateDiffGenerator {
 mod test {
     use crate::database::{
         animation::{AnimSelector, UnitForm, UnitSelector},
-        BC_ASSET_PATH,
+        catalog::UnitCatalog,
+        source::DirSource,
     };
 
     use super::*;
@@ -825,30 +1067,45 @@ mod test {
     fn maanim() {
         println!(
             "{:#?}",
-            Maanim::load("assets/org/unit/697/s/697_s00.maanim").unwrap()
+            Maanim::load(&DirSource::default(), "unit/697/s/697_s00.maanim").unwrap()
         );
     }
 
+    #[test]
+    fn maanim_from_bytes() {
+        let maanim = b"[modelanim:animation2]\n1\n2\n\
+            2,12,1,0,0,\n2\n0,1000,4,1\n15,0,0,0\n\
+            1,11,-1,0,0,\n3\n0,0,0,0\n10,900,1,0\n20,0,0,0\n";
+        let anim = Maanim::from_reader(&maanim[..]).unwrap();
+        assert_eq!(anim.period, 20);
+        assert_eq!(anim.parts[0].id, 1);
+        assert_eq!(anim.parts[1].eases[0].easing, Easing::Sine(Sign::Positive));
+
+        let crlf = std::str::from_utf8(maanim).unwrap().replace('\n', "\r\n");
+        let anim2: Maanim = format!("\u{feff}{crlf}\r\n\r\n").parse().unwrap();
+        assert_eq!(anim2.period, anim.period);
+        assert_eq!(anim2.parts.len(), 2);
+    }
+
     #[test]
     fn load_all_unit() {
-        let unit_path = Path::new("org/unit");
+        let source = DirSource::default();
+        let catalog = UnitCatalog::scan(&source);
         let mut max = 0;
         // unit読み込み
-        for i in 0..=697 {
-            for c in ['f', 's', 'c'] {
-                for j in 0..4 {
-                    let path =
-                        unit_path.join(format!("{0:>03}/{c}/{0:>03}_{c}{1:>02}.maanim", i, j));
-                    // println!("{}", path.is_file());
-                    if let Ok(anim) = Maanim::load(path) {
-                        let sum = anim
-                            .parts
-                            .into_iter()
-                            .map(|part| part.frame_end - part.frame_start)
-                            .sum();
-                        if max < sum {
-                            max = sum;
-                        }
+        for (selector, anims) in catalog.iter() {
+            if !matches!(selector, UnitSelector::Unit(_)) {
+                continue;
+            }
+            for &anim in anims {
+                if let Ok(anim) = selector.load_maanim(&source, anim) {
+                    let sum = anim
+                        .parts
+                        .into_iter()
+                        .map(|part| part.frame_end - part.frame_start)
+                        .sum();
+                    if max < sum {
+                        max = sum;
                     }
                 }
             }
@@ -858,33 +1115,153 @@ mod test {
 
     #[test]
     fn load_all_enemy() {
-        let enemy_path = Path::new("assets/org/enemy");
-        for i in 0..=634 {
-            for j in 0..4 {
-                let path = enemy_path.join(format!("{0:>03}/{0:>03}_e{1:>02}.maanim", i, j));
-                if let Ok(anim) = Maanim::load(path) {
+        let source = DirSource::default();
+        let catalog = UnitCatalog::scan(&source);
+        for id in catalog.enemies() {
+            let selector = UnitSelector::Enemy(id);
+            for &anim_selector in catalog.anims(selector).unwrap_or_default() {
+                if let Ok(anim) = selector.load_maanim(&source, anim_selector) {
                     if let Some(part) = anim
                         .parts
                         .iter()
                         .find(|elem| elem.modification == Modification::Id)
                     {
-                        println!("({i}, {j}): {:#?}", part);
+                        println!("({id}, {anim_selector:?}): {:#?}", part);
                     }
                 }
             }
-            for j in 0..3 {
-                let path = enemy_path.join(format!("{0:>03}/{0:>03}_e_zombie{1:>02}.maanim", i, j));
-                if let Ok(anim) = Maanim::load(path) {
-                    if let Some(part) = anim
-                        .parts
-                        .iter()
-                        .find(|elem| elem.modification == Modification::Id)
-                    {
-                        println!("({i}, zombie{j}): {:#?}", part);
-                    }
-                }
+        }
+    }
+
+    #[test]
+    fn fractional_frame() {
+        let models = Mamodels::from_reader(
+            &b"[modelanim:model2]\n1\n2\n\
+            -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,1,0,0,0,0,1000,1000,0,1000,0\n\
+            1000,3600,1000\n"[..],
+        )
+        .unwrap();
+        let maanim = Maanim::from_reader(
+            &b"[modelanim:animation2]\n1\n3\n\
+            1,4,-1,0,0\n3\n0,0,4,1\n10,-500,3,0\n20,700,3,0\n\
+            1,11,1,0,0\n2\n0,0,0,0\n8,3600,0,0\n\
+            1,2,-1,0,0\n2\n0,0,0,0\n5,1,0,0\n"[..],
+        )
+        .unwrap();
+        let mut generator = from_data::StateGenerator::from_anim(maanim, &models);
+        let frames: Vec<_> = (0..60).map(|_| generator.next_state()).collect();
+        // 整数のフレームは1フレームずつ進めたものと一致する
+        for (i, expected) in frames.iter().enumerate() {
+            let state = generator.state_at(i as f32);
+            for (a, b) in state.states.iter().zip(&expected.states) {
+                assert_eq!(format!("{a:?}"), format!("{b:?}"), "frame {i}");
             }
         }
+        // 途中のフレームは前後のフレームの間の値になる
+        for i in 0..19 {
+            let (a, b) = (&frames[i].states[1], &frames[i + 1].states[1]);
+            let mid = generator.state_at(i as f32 + 0.5);
+            let mid = &mid.states[1];
+            let (lo, hi) = (a.x.min(b.x), a.x.max(b.x));
+            assert!((lo..=hi).contains(&mid.x), "frame {i}: {} {} {}", a.x, mid.x, b.x);
+            assert_eq!(mid.img, a.img);
+        }
+        // ループの直前は先頭に戻らず最後のキーフレームに向かう
+        let before_loop = generator.state_at(19.5).states[1].x;
+        assert!(frames[19].states[1].x < before_loop && before_loop < 700.);
+        let quarter = generator.state_at(0.25).states[1].angle;
+        assert_eq!(quarter, 3600. / 8. / 4.);
+        assert_eq!(generator.advance(0.5 / from_data::FPS).states[1].angle, 3600. / 8. / 2.);
+    }
+
+    #[test]
+    fn playback() {
+        let models = Mamodels::from_reader(
+            &b"[modelanim:model2]\n1\n1\n-1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n1000,3600,1000\n"[..],
+        )
+        .unwrap();
+        let maanim = Maanim::from_reader(
+            &b"[modelanim:animation2]\n1\n1\n0,4,-1,0,0\n2\n0,0,0,0\n10,100,0,0\n"[..],
+        )
+        .unwrap();
+        let mut generator = from_data::StateGenerator::from_anim(maanim, &models);
+        let x = |generator: &from_data::StateGenerator| generator.current_state().states[0].x;
+        assert_eq!(generator.period(), 10);
+
+        generator.advance(2. / from_data::FPS);
+        assert_eq!(x(&generator), 20.);
+        generator.pause();
+        generator.advance(1.);
+        assert_eq!(generator.time(), 2.);
+
+        generator.seek(4.5);
+        generator.step_forward();
+        assert_eq!(generator.time(), 5.);
+        generator.step_backward();
+        generator.step_backward();
+        assert_eq!(x(&generator), 30.);
+        assert!(!generator.is_playing());
+
+        generator.play();
+        generator.set_speed(0.5);
+        generator.set_reverse(true);
+        generator.advance(2. / from_data::FPS);
+        assert_eq!(generator.time(), 2.);
+        generator.advance(6. / from_data::FPS);
+        // 0より前は折り返す
+        assert_eq!(generator.time(), 9.);
+        assert_eq!(x(&generator), 90.);
+    }
+
+    #[test]
+    fn period_from_maanim() {
+        let models = Mamodels::from_reader(
+            &b"[modelanim:model2]\n1\n1\n-1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n1000,3600,1000\n"[..],
+        )
+        .unwrap();
+        // 5フレーム目から始まるパーツ: データは0から10まであるが周期は5
+        let maanim = Maanim::from_reader(
+            &b"[modelanim:animation2]\n1\n1\n0,4,1,0,0\n2\n5,0,0,0\n10,50,0,0\n"[..],
+        )
+        .unwrap();
+        assert_eq!(maanim.period(), 5);
+        let mut generator = from_data::StateGenerator::from_anim(maanim, &models);
+        assert_eq!(generator.period(), 5);
+
+        generator.seek(-1.);
+        assert_eq!(generator.time(), 4.);
+        generator.seek(1.);
+        generator.set_reverse(true);
+        generator.advance(2. / from_data::FPS);
+        assert_eq!(generator.time(), 4.);
+    }
+
+    #[test]
+    fn finite_loop() {
+        let models = Mamodels::from_reader(
+            &b"[modelanim:model2]\n1\n2\n-1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+            0,0,0,0,0,0,0,0,1000,1000,0,1000,0\n1000,3600,1000\n"[..],
+        )
+        .unwrap();
+        // パーツ0はループ回数1で10フレーム、パーツ1で全体の周期を20にする
+        let maanim = Maanim::from_reader(
+            &b"[modelanim:animation2]\n1\n2\n0,4,1,0,0\n2\n0,0,0,0\n10,100,0,0\n\
+            1,4,-1,0,0\n2\n0,0,0,0\n20,0,0,0\n"[..],
+        )
+        .unwrap();
+        let generator = from_data::StateGenerator::from_anim(maanim.clone(), &models);
+        let x = |frame: f32| generator.state_at(frame).states[0].x;
+        assert_eq!(x(5.), 50.);
+        // 最後のキーフレームの後は周期の終わりまでその値のまま
+        assert_eq!(x(15.), 100.);
+        // 以前は100のまま止まっていたが、全体の周期で先頭に戻る
+        assert_eq!(x(25.), 50.);
+
+        let mut streaming = maanim.into_state_generator(&models);
+        let states: Vec<f32> = (0..=25).map(|_| streaming.next_state().states[0].x).collect();
+        assert_eq!(states[15], 100.);
+        assert_eq!(states[25], 50.);
     }
 
     use std::io::BufWriter;
@@ -0,0 +1,337 @@
+//! 計算済みのアニメーション(StateDiffData)のキャッシュ
+//!
+//! 1ユニット(1形態)の全AnimSelector分を1ファイルにまとめる。
+//! ```text
+//! "BCAC" version:u32 hash:u64 (アニメーション数, [有無, period, DiffData数, [DiffData]])
+//! DiffData: id border partial_loop modification len [data]
+//! ```
+//! ヘッダ以外の数は全て可変長(LEB128、符号付きはzigzag)で書く。
+//! hashは元のmamodelとmaanimのFNV-1a 64で、一致しなければ作り直す。
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use bevy::prelude::Resource;
+
+use super::{DiffData, Modification, StateDiffData};
+use crate::database::{
+    animation::{AnimSelector, UnitSelector},
+    error::{Error, ErrorKind},
+    source::AssetSource,
+    validate,
+};
+
+const MAGIC: &[u8; 4] = b"BCAC";
+/// 形式やStateDiffDataの計算方法を変えたら上げる
+pub const CACHE_VERSION: u32 = 3;
+
+/// 1ユニット分の計算済みのアニメーション
+/// AnimSelector::ALLの順で、ファイルが無いか壊れているものはNone
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnitTracks {
+    tracks: Vec<Option<StateDiffData>>,
+}
+
+impl UnitTracks {
+    /// mamodelと全maanimを読み込んで計算する
+    pub fn build(source: &dyn AssetSource, selector: UnitSelector) -> Result<Self, Error> {
+        let models = selector.load_mamodel(source)?;
+        let tracks = AnimSelector::ALL
+            .into_iter()
+            .map(|anim_selector| {
+                let anim = selector.load_maanim(source, anim_selector).ok()?;
+                match validate::check(&models, &[], std::slice::from_ref(&anim)) {
+                    Ok(_) => Some(StateDiffData::from_anim(anim, &models)),
+                    Err(err) => {
+                        println!("invalid animation ({selector:?}, {anim_selector:?})\nerror info: {err:#?}");
+                        None
+                    }
+                }
+            })
+            .collect();
+        Ok(Self { tracks })
+    }
+
+    pub fn get(&self, anim: AnimSelector) -> Option<&StateDiffData> {
+        self.tracks.get(anim as usize)?.as_ref()
+    }
+
+    pub fn encode(&self, hash: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.extend(MAGIC);
+        buf.extend(CACHE_VERSION.to_le_bytes());
+        buf.extend(hash.to_le_bytes());
+        put_uint(&mut buf, self.tracks.len() as u64);
+        for track in &self.tracks {
+            let Some(StateDiffData { period, parts: diffs }) = track else {
+                buf.push(0);
+                continue;
+            };
+            buf.push(1);
+            put_uint(&mut buf, *period as u64);
+            put_uint(&mut buf, diffs.len() as u64);
+            for diff in diffs.iter() {
+                put_uint(&mut buf, diff.id as u64);
+                put_uint(&mut buf, diff.border as u64);
+                buf.push(diff.partial_loop as u8);
+                put_int(&mut buf, i32::from(diff.modification) as i64);
+                put_uint(&mut buf, diff.data.len() as u64);
+                for &v in diff.data.iter() {
+                    put_int(&mut buf, v as i64);
+                }
+            }
+        }
+        buf
+    }
+
+    /// バージョンかhashが違えばエラー
+    pub fn decode(bytes: &[u8], hash: u64) -> Result<Self, Error> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(4)? != MAGIC {
+            return Err(broken("先頭がBCACでない"));
+        }
+        let version = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+        if version != CACHE_VERSION {
+            return Err(broken(format!(
+                "バージョン({version})が{CACHE_VERSION}でない"
+            )));
+        }
+        if u64::from_le_bytes(reader.take(8)?.try_into().unwrap()) != hash {
+            return Err(broken("元のファイルが変更されている"));
+        }
+        let len = reader.len()?;
+        let mut tracks = Vec::with_capacity(len.min(AnimSelector::ALL.len()));
+        for _ in 0..len {
+            if reader.take(1)?[0] == 0 {
+                tracks.push(None);
+                continue;
+            }
+            let period = reader
+                .uint()?
+                .try_into()
+                .map_err(|_| broken("periodが大きすぎる"))?;
+            let diff_len = reader.len()?;
+            let mut diffs = Vec::with_capacity(diff_len.min(bytes.len()));
+            for _ in 0..diff_len {
+                let id = reader.uint()?.try_into().map_err(|_| broken("idが大きすぎる"))?;
+                let border = reader
+                    .uint()?
+                    .try_into()
+                    .map_err(|_| broken("borderが大きすぎる"))?;
+                let partial_loop = reader.take(1)?[0] != 0;
+                let modification = Modification::try_from(reader.int()? as i32)?;
+                let data_len = reader.len()?;
+                let data = (0..data_len)
+                    .map(|_| reader.int().map(|v| v as i32))
+                    .collect::<Result<_, _>>()?;
+                diffs.push(DiffData {
+                    id,
+                    border,
+                    partial_loop,
+                    modification,
+                    data,
+                });
+            }
+            tracks.push(Some(StateDiffData {
+                period,
+                parts: diffs.into_boxed_slice(),
+            }));
+        }
+        if reader.pos != bytes.len() {
+            return Err(broken("末尾に余分なデータがある"));
+        }
+        Ok(Self { tracks })
+    }
+}
+
+fn broken(msg: impl Into<String>) -> Error {
+    Error::new(ErrorKind::FileFormatError, msg.into())
+}
+
+fn put_uint(buf: &mut Vec<u8>, mut v: u64) {
+    loop {
+        let b = (v & 0x7f) as u8;
+        v >>= 7;
+        if v == 0 {
+            buf.push(b);
+            return;
+        }
+        buf.push(b | 0x80);
+    }
+}
+
+fn put_int(buf: &mut Vec<u8>, v: i64) {
+    put_uint(buf, ((v << 1) ^ (v >> 63)) as u64);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], Error> {
+        let slice = self
+            .bytes
+            .get(self.pos..self.pos + n)
+            .ok_or_else(|| broken("データが途中で終わっている"))?;
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn uint(&mut self) -> Result<u64, Error> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let b = self.take(1)?[0];
+            v |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(v);
+            }
+        }
+        Err(broken("可変長の数が長すぎる"))
+    }
+
+    fn int(&mut self) -> Result<i64, Error> {
+        let v = self.uint()?;
+        Ok((v >> 1) as i64 ^ -((v & 1) as i64))
+    }
+
+    /// 残りのバイト数より大きい長さは壊れているとみなす
+    fn len(&mut self) -> Result<usize, Error> {
+        let len = self.uint()?;
+        if len > (self.bytes.len() - self.pos) as u64 {
+            return Err(broken("長さがファイルの大きさを超えている"));
+        }
+        Ok(len as usize)
+    }
+}
+
+/// FNV-1a 64
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// mamodelと全maanimの内容から計算する
+/// 無いファイルとあるファイルは区別する
+pub fn source_hash(source: &dyn AssetSource, selector: UnitSelector) -> u64 {
+    let files = std::iter::once(selector.mamodels()).chain(
+        AnimSelector::ALL
+            .into_iter()
+            .map(|anim| selector.maanim(anim)),
+    );
+    files.fold(0xcbf2_9ce4_8422_2325, |hash, file| {
+        match source.read(Path::new(&file)) {
+            Ok(bytes) => fnv1a(fnv1a(hash, &(bytes.len() as u64).to_le_bytes()), &bytes),
+            Err(_) => fnv1a(hash, &[0xff; 8]),
+        }
+    })
+}
+
+/// キャッシュを置くディレクトリ
+#[derive(Resource, Clone, Debug)]
+pub struct TrackCache {
+    dir: PathBuf,
+}
+
+impl Default for TrackCache {
+    fn default() -> Self {
+        Self::new("assets/cache")
+    }
+}
+
+impl TrackCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    pub fn path(&self, selector: UnitSelector) -> PathBuf {
+        self.dir.join(selector.filename() + ".animcache")
+    }
+
+    /// キャッシュが使えればそれを、使えなければ計算して保存したものを返す
+    pub fn load(&self, source: &dyn AssetSource, selector: UnitSelector) -> Result<UnitTracks, Error> {
+        let hash = source_hash(source, selector);
+        let path = self.path(selector);
+        if let Ok(tracks) = fs::read(&path)
+            .map_err(Error::from)
+            .and_then(|bytes| UnitTracks::decode(&bytes, hash))
+        {
+            return Ok(tracks);
+        }
+        let tracks = UnitTracks::build(source, selector)?;
+        if let Err(err) = self.store(&path, &tracks.encode(hash)) {
+            println!("{err}");
+        }
+        Ok(tracks)
+    }
+
+    fn store(&self, path: &Path, bytes: &[u8]) -> Result<(), Error> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::from(e).with_path(parent))?;
+        }
+        fs::write(path, bytes).map_err(|e| Error::from(e).with_path(path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{animation::UnitForm, source::MemorySource};
+
+    const MAMODEL: &str = "[modelanim:model2]\n1\n2\n\
+        -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+        0,0,0,1,0,0,0,0,1000,1000,0,1000,0\n\
+        1000,3600,1000\n";
+    const MAANIM: &str = "[modelanim:animation2]\n1\n2\n\
+        1,4,-1,0,0\n3\n0,0,0,0\n10,-500,2,3\n20,70000,4,-1\n\
+        1,11,1,0,0\n1\n0,90,0,0\n";
+
+    fn source(selector: UnitSelector) -> MemorySource {
+        let mut source = MemorySource::new();
+        source
+            .insert(selector.mamodels(), MAMODEL)
+            .insert(selector.maanim(AnimSelector::Walk), MAANIM)
+            .insert(selector.maanim(AnimSelector::BurrowUp), MAANIM);
+        source
+    }
+
+    #[test]
+    fn round_trip() {
+        let selector = UnitSelector::Unit((1, UnitForm::Form1));
+        let source = source(selector);
+        let tracks = UnitTracks::build(&source, selector).unwrap();
+        assert!(tracks.get(AnimSelector::Walk).is_some());
+        assert!(tracks.get(AnimSelector::Idle).is_none());
+
+        let hash = source_hash(&source, selector);
+        let bytes = tracks.encode(hash);
+        assert_eq!(UnitTracks::decode(&bytes, hash).unwrap(), tracks);
+        assert!(UnitTracks::decode(&bytes, hash ^ 1).is_err());
+        assert!(UnitTracks::decode(&bytes[..bytes.len() - 1], hash).is_err());
+        let mut old = bytes.clone();
+        old[4] = 0;
+        assert!(UnitTracks::decode(&old, hash).is_err());
+    }
+
+    #[test]
+    fn invalidate() {
+        let selector = UnitSelector::Enemy(7);
+        let mut source = source(selector);
+        let dir = std::env::temp_dir().join(format!("bc-track-cache-{}", std::process::id()));
+        let cache = TrackCache::new(&dir);
+
+        let tracks = cache.load(&source, selector).unwrap();
+        assert!(cache.path(selector).is_file());
+        assert_eq!(cache.load(&source, selector).unwrap(), tracks);
+
+        source.insert(selector.maanim(AnimSelector::Walk), MAANIM.replace("-500", "500"));
+        let changed = cache.load(&source, selector).unwrap();
+        assert_ne!(changed, tracks);
+        assert_eq!(changed, UnitTracks::build(&source, selector).unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+//! アニメーションを1フレームずつ計算するものの共通の形と、2つの実装の突き合わせ
+//!
+//! [`super::StateGenerator`]はmaanimから差分を順に作り、
+//! [`super::from_data::StateGenerator`]は先に全フレームの値を計算しておく。
+//! 後者の方が速いので、前者と同じ結果になることを[`compare_source`]で確かめる。
+
+use std::fmt;
+use std::path::Path;
+
+use super::{from_data, Maanim, StateGenerator};
+use crate::database::{
+    animation::{AnimSelector, State, UnitSelector, UnitState},
+    catalog::UnitCatalog,
+    source::AssetSource,
+    validate, Mamodels,
+};
+
+pub trait AnimationEvaluator {
+    fn from_anim(maanim: Maanim, models: &Mamodels) -> Self
+    where
+        Self: Sized;
+
+    /// 次のフレームの状態(最初の呼び出しでフレーム0)
+    fn next_state(&mut self) -> UnitState;
+
+    fn period(&self) -> u32;
+}
+
+impl AnimationEvaluator for StateGenerator {
+    fn from_anim(maanim: Maanim, models: &Mamodels) -> Self {
+        maanim.into_state_generator(models)
+    }
+
+    fn next_state(&mut self) -> UnitState {
+        StateGenerator::next_state(self)
+    }
+
+    fn period(&self) -> u32 {
+        self.diff_generator.maanim.period
+    }
+}
+
+impl AnimationEvaluator for from_data::StateGenerator {
+    fn from_anim(maanim: Maanim, models: &Mamodels) -> Self {
+        from_data::StateGenerator::from_anim(maanim, models)
+    }
+
+    fn next_state(&mut self) -> UnitState {
+        from_data::StateGenerator::next_state(self)
+    }
+
+    fn period(&self) -> u32 {
+        from_data::StateGenerator::period(self)
+    }
+}
+
+/// 最初に結果が食い違ったところ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    pub frame: u32,
+    pub part: usize,
+    pub field: &'static str,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frame {}, part {}, {}: expected {}, actual {}",
+            self.frame, self.part, self.field, self.expected, self.actual
+        )
+    }
+}
+
+/// 最初に違う値(フィールド名, a, b)
+fn diff_state(a: &State, b: &State) -> Option<(&'static str, String, String)> {
+    macro_rules! fields {
+        ($($field:ident),*) => {
+            $(
+                if a.$field != b.$field {
+                    return Some((
+                        stringify!($field),
+                        format!("{:?}", a.$field),
+                        format!("{:?}", b.$field),
+                    ));
+                }
+            )*
+        };
+    }
+    fields!(
+        parent,
+        img,
+        zorder,
+        x,
+        y,
+        pivotx,
+        pivoty,
+        scale,
+        scalex,
+        scaley,
+        angle,
+        opacity,
+        extendx,
+        extendy,
+        glow,
+        sheet,
+        horizontal_flip,
+        vertical_flip
+    );
+    None
+}
+
+/// expectedとactualをframes回進めて比べる
+pub fn diverge(
+    expected: &mut dyn AnimationEvaluator,
+    actual: &mut dyn AnimationEvaluator,
+    frames: u32,
+) -> Option<Divergence> {
+    (0..frames).find_map(|frame| {
+        let (a, b) = (expected.next_state(), actual.next_state());
+        a.states
+            .iter()
+            .zip(&b.states)
+            .enumerate()
+            .find_map(|(part, (a, b))| {
+                let (field, expected, actual) = diff_state(a, b)?;
+                Some(Divergence {
+                    frame,
+                    part,
+                    field,
+                    expected,
+                    actual,
+                })
+            })
+    })
+}
+
+/// 2つの実装でmaanimを計算して比べる
+/// ループの折り返しも確かめるため、periodの3周分進める
+pub fn compare<A: AnimationEvaluator, B: AnimationEvaluator>(
+    maanim: Maanim,
+    models: &Mamodels,
+) -> Option<Divergence> {
+    let frames = maanim.period() * 3 + 1;
+    let mut a = A::from_anim(maanim.clone(), models);
+    let mut b = B::from_anim(maanim, models);
+    diverge(&mut a, &mut b, frames)
+}
+
+/// source内の全アニメーションで、maanimから直接計算したものと計算済みのデータから計算したものを比べる
+/// 読み込めないものとvalidate::checkでエラーになるものは飛ばす
+pub fn compare_source(
+    source: &dyn AssetSource,
+) -> Vec<(UnitSelector, AnimSelector, Divergence)> {
+    let catalog = UnitCatalog::scan(source);
+    let mut result = Vec::new();
+    for (selector, anims) in catalog.iter() {
+        if !source.is_file(Path::new(&selector.mamodels())) {
+            continue;
+        }
+        let Ok(models) = selector.load_mamodel(source) else {
+            continue;
+        };
+        for &anim_selector in anims {
+            let Ok(maanim) = selector.load_maanim(source, anim_selector) else {
+                continue;
+            };
+            if validate::check(&models, &[], std::slice::from_ref(&maanim)).is_err() {
+                continue;
+            }
+            if let Some(divergence) =
+                compare::<StateGenerator, from_data::StateGenerator>(maanim, &models)
+            {
+                result.push((selector, anim_selector, divergence));
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::source::{DirSource, MemorySource};
+
+    const MAMODEL: &str = "[modelanim:model2]\n1\n3\n\
+        -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+        0,0,0,1,0,0,0,0,1000,1000,0,1000,0\n\
+        1,0,1,2,0,0,0,0,1000,1000,0,1000,0\n\
+        1000,3600,1000\n";
+
+    /// 全種類のイージング、途中から始まるもの、ループするものを含む
+    const MAANIM: &str = "[modelanim:animation2]\n1\n9\n\
+        0,4,-1,0,0\n3\n0,0,0,0\n7,333,1,0\n13,-20,0,0\n\
+        1,5,-1,0,0\n3\n0,10,2,3\n9,-470,2,-2\n20,15,0,0\n\
+        1,11,1,0,0\n4\n0,0,4,1\n11,1800,4,-1\n17,900,4,0\n24,3600,0,0\n\
+        2,12,-1,0,0\n5\n0,1000,3,0\n4,300,3,0\n9,800,3,0\n15,0,0,0\n21,500,0,0\n\
+        2,2,-1,0,0\n3\n0,0,1,0\n6,1,1,0\n12,2,0,0\n\
+        2,8,2,0,0\n2\n-5,1000,0,0\n10,1500,0,0\n\
+        1,13,-1,0,0\n2\n0,0,1,0\n4,1,0,0\n\
+        0,6,-1,0,0\n1\n0,77,0,0\n\
+        2,50,-1,0,0\n2\n0,1000,2,1\n6,2500,0,0\n";
+
+    #[test]
+    fn conformance() {
+        let models = Mamodels::from_reader(MAMODEL.as_bytes()).unwrap();
+        let maanim = Maanim::from_reader(MAANIM.as_bytes()).unwrap();
+        assert_eq!(
+            compare::<StateGenerator, from_data::StateGenerator>(maanim, &models),
+            None
+        );
+    }
+
+    #[test]
+    fn reports_divergence() {
+        let models = Mamodels::from_reader(MAMODEL.as_bytes()).unwrap();
+        let maanim = Maanim::from_reader(MAANIM.as_bytes()).unwrap();
+        let shifted = Maanim::from_reader(MAANIM.replace("7,333", "7,334").as_bytes()).unwrap();
+        let mut a = StateGenerator::from_anim(maanim, &models);
+        let mut b = from_data::StateGenerator::from_anim(shifted, &models);
+        let divergence = diverge(&mut a, &mut b, 30).unwrap();
+        // 333 * 3 / 7 = 142.7と334 * 3 / 7 = 143.1で最初に整数部が変わる
+        assert_eq!((divergence.frame, divergence.part, divergence.field), (3, 0, "x"));
+        assert_eq!(divergence.to_string(), "frame 3, part 0, x: expected 142.0, actual 143.0");
+    }
+
+    #[test]
+    fn conformance_source() {
+        let selector = UnitSelector::Enemy(5);
+        let mut source = MemorySource::new();
+        source
+            .insert(selector.mamodels(), MAMODEL)
+            .insert(selector.maanim(AnimSelector::Walk), MAANIM)
+            .insert(selector.maanim(AnimSelector::Idle), "broken");
+        assert!(compare_source(&source).is_empty());
+    }
+
+    /// アセットのディレクトリが必要なので`cargo test -- --ignored`で実行する
+    #[test]
+    #[ignore]
+    fn conformance_assets() {
+        let source = DirSource::default();
+        assert!(!UnitCatalog::scan(&source).is_empty(), "ユニットが無い");
+        let divergences = compare_source(&source);
+        assert!(divergences.is_empty(), "{divergences:?}");
+    }
+}
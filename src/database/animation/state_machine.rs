@@ -0,0 +1,466 @@
+//! ユニットごとのアニメーションの切り替え
+//!
+//! ```text
+//! Walk -> Attack -> Idle -> Walk
+//! 任意 -> HitBack -> Walk
+//! Walk -> BurrowDown -> BurrowMove -> BurrowUp -> Walk
+//! ```
+//! 矢印のうちWalkから出るものとBurrowMove -> BurrowUp、HitBackはAnimRequestで、
+//! それ以外はアニメーションが最後まで再生されたときに切り替わる。
+//!
+//! 再生の開始・ループ・終了と、設定したフレームに来たことはイベントで知らせる。
+
+use bevy::{ecs::system::SystemParam, prelude::*};
+
+use super::{
+    state_gen::{cache::UnitTracks, from_data::StateGenerator, StateDiffData},
+    AnimSelector,
+};
+use crate::database::Mamodels;
+
+/// entityのアニメーションをanimに切り替えるよう頼む
+/// 今のアニメーションから切り替えられなければ無視される
+#[derive(Clone, Copy, Debug)]
+pub struct AnimRequest {
+    pub entity: Entity,
+    pub anim: AnimSelector,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationStarted {
+    pub entity: Entity,
+    pub anim: AnimSelector,
+}
+
+/// 切り替わらないアニメーション(Walkなど)が1周した
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationLooped {
+    pub entity: Entity,
+    pub anim: AnimSelector,
+}
+
+/// 最後まで再生して次のアニメーションに切り替わった
+/// AnimRequestで中断されたときは送らない
+#[derive(Clone, Copy, Debug)]
+pub struct AnimationFinished {
+    pub entity: Entity,
+    pub anim: AnimSelector,
+}
+
+/// AnimStateMachine::with_markerで設定したフレームに来た
+#[derive(Clone, Debug)]
+pub struct FrameMarker {
+    pub entity: Entity,
+    pub anim: AnimSelector,
+    pub frame: u32,
+    pub name: String,
+}
+
+/// エンティティの付いていないイベント
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum AnimSignal {
+    Started(AnimSelector),
+    Looped(AnimSelector),
+    Finished(AnimSelector),
+    Marker {
+        anim: AnimSelector,
+        frame: u32,
+        name: String,
+    },
+}
+
+#[derive(Clone, Debug)]
+struct MarkerDef {
+    anim: AnimSelector,
+    frame: u32,
+    name: String,
+}
+
+/// 最後まで再生したときに切り替えるアニメーション(Noneならループし続ける)
+pub fn next_anim(anim: AnimSelector) -> Option<AnimSelector> {
+    use AnimSelector::*;
+    match anim {
+        Walk => None,
+        Attack => Some(Idle),
+        Idle | HitBack | BurrowUp => Some(Walk),
+        BurrowDown => Some(BurrowMove),
+        BurrowMove => Some(BurrowUp),
+    }
+}
+
+/// fromの再生中にtoへのAnimRequestを受け付けるか
+pub fn can_request(from: AnimSelector, to: AnimSelector) -> bool {
+    use AnimSelector::*;
+    matches!(
+        (from, to),
+        (_, HitBack)
+            | (Walk | Idle, Attack)
+            | (Idle, Walk)
+            | (Walk, Idle)
+            | (Walk, BurrowDown)
+            | (BurrowMove, BurrowUp)
+    )
+}
+
+/// StateGeneratorと同じエンティティに付ける
+#[derive(Component, Clone, Debug)]
+pub struct AnimStateMachine {
+    tracks: UnitTracks,
+    models: Mamodels,
+    current: AnimSelector,
+    /// 切り替えにかける秒数(0なら即座に切り替える)
+    transition: f32,
+    markers: Vec<MarkerDef>,
+    /// イベントを調べ終えたStateGeneratorの時間
+    cursor: f32,
+    signals: Vec<AnimSignal>,
+}
+
+impl AnimStateMachine {
+    /// Walkから始める
+    pub fn new(tracks: UnitTracks, models: Mamodels) -> Self {
+        Self {
+            tracks,
+            models,
+            current: AnimSelector::Walk,
+            transition: 0.,
+            markers: Vec::new(),
+            cursor: 0.,
+            signals: Vec::new(),
+        }
+    }
+
+    /// animのframeに来たらFrameMarkerを送る(攻撃の発生フレームなど)
+    pub fn with_marker(mut self, anim: AnimSelector, frame: u32, name: impl Into<String>) -> Self {
+        self.markers.push(MarkerDef {
+            anim,
+            frame,
+            name: name.into(),
+        });
+        self
+    }
+
+    pub fn with_transition(mut self, seconds: f32) -> Self {
+        self.set_transition(seconds);
+        self
+    }
+
+    pub fn transition(&self) -> f32 {
+        self.transition
+    }
+
+    pub fn set_transition(&mut self, seconds: f32) {
+        self.transition = seconds.max(0.);
+    }
+
+    pub fn current(&self) -> AnimSelector {
+        self.current
+    }
+
+    /// currentを再生するStateGenerator
+    pub fn generator(&self) -> StateGenerator {
+        StateGenerator::from_data(self.track(self.current), &self.models)
+    }
+
+    fn track(&self, anim: AnimSelector) -> StateDiffData {
+        self.tracks.get(anim).cloned().unwrap_or_default()
+    }
+
+    /// animを先頭から再生する
+    /// ファイルが無いアニメーションは飛ばしてnext_animに進む
+    pub fn start(&mut self, mut anim: AnimSelector, generator: &mut StateGenerator) {
+        for _ in 0..AnimSelector::ALL.len() {
+            if self.tracks.get(anim).is_some() {
+                break;
+            }
+            match next_anim(anim) {
+                Some(next) => anim = next,
+                None => break,
+            }
+        }
+        self.current = anim;
+        self.cursor = 0.;
+        self.signals.push(AnimSignal::Started(anim));
+        generator.crossfade(self.track(anim), self.transition);
+    }
+
+    /// 受け付けたらtrue
+    /// ファイルが無いアニメーションは受け付けず、今のアニメーションのままにする
+    pub fn request(&mut self, anim: AnimSelector, generator: &mut StateGenerator) -> bool {
+        if !can_request(self.current, anim) || self.tracks.get(anim).is_none() {
+            return false;
+        }
+        self.start(anim, generator);
+        true
+    }
+
+    /// 最後まで再生していれば次のアニメーションに切り替える
+    /// 逆再生中は切り替えない
+    pub fn update(&mut self, generator: &mut StateGenerator) -> Option<AnimSelector> {
+        self.scan(generator.time(), generator.period());
+        let finished = !generator.is_reverse() && generator.time() >= generator.period() as f32;
+        let next = next_anim(self.current).filter(|_| finished)?;
+        self.signals.push(AnimSignal::Finished(self.current));
+        self.start(next, generator);
+        Some(self.current)
+    }
+
+    /// 前回から時間が進んだ分のループとマーカーを調べる
+    /// 戻ったとき(逆再生、seek)は何もしない
+    fn scan(&mut self, now: f32, period: u32) {
+        let prev = std::mem::replace(&mut self.cursor, now);
+        if now <= prev {
+            return;
+        }
+        let anim = self.current;
+        let loops = next_anim(anim).is_none() && period > 0;
+        // 大きく進んだときは境目やマーカーを何度も越えるので、越えた回数だけ時間順に送る
+        let mut events: Vec<(f32, AnimSignal)> = Vec::new();
+        if loops {
+            let period = period as f32;
+            // (prev, now]に入る周期の境目
+            let first = (prev / period).floor() as u32 + 1;
+            let last = (now / period).floor() as u32;
+            events.extend((first..=last).map(|k| (k as f32 * period, AnimSignal::Looped(anim))));
+        }
+        for marker in self.markers.iter().filter(|marker| marker.anim == anim) {
+            let frame = marker.frame as f32;
+            let signal = || AnimSignal::Marker {
+                anim,
+                frame: marker.frame,
+                name: marker.name.clone(),
+            };
+            if loops {
+                let period = period as f32;
+                if frame >= period {
+                    continue;
+                }
+                // [prev, now)に入るframe + k * period
+                let first = ((prev - frame) / period).ceil().max(0.) as u32;
+                events.extend(
+                    (first..)
+                        .map(|k| frame + k as f32 * period)
+                        .take_while(|&time| time < now)
+                        .map(|time| (time, signal())),
+                );
+            } else if (prev..now).contains(&frame) {
+                events.push((frame, signal()));
+            }
+        }
+        // 同じ時間なら周の境目を先にする(安定ソートなのでマーカー同士は登録順)
+        events.sort_by(|(a, a_signal), (b, b_signal)| {
+            let is_marker = |signal: &AnimSignal| matches!(signal, AnimSignal::Marker { .. });
+            a.total_cmp(b)
+                .then_with(|| is_marker(a_signal).cmp(&is_marker(b_signal)))
+        });
+        self.signals.extend(events.into_iter().map(|(_, signal)| signal));
+    }
+
+    /// 溜まったイベントを取り出す
+    pub fn take_signals(&mut self) -> Vec<AnimSignal> {
+        std::mem::take(&mut self.signals)
+    }
+}
+
+#[derive(SystemParam)]
+pub struct AnimationEvents<'w> {
+    started: EventWriter<'w, AnimationStarted>,
+    looped: EventWriter<'w, AnimationLooped>,
+    finished: EventWriter<'w, AnimationFinished>,
+    markers: EventWriter<'w, FrameMarker>,
+}
+
+impl AnimationEvents<'_> {
+    fn send(&mut self, entity: Entity, signal: AnimSignal) {
+        match signal {
+            AnimSignal::Started(anim) => self.started.send(AnimationStarted { entity, anim }),
+            AnimSignal::Looped(anim) => self.looped.send(AnimationLooped { entity, anim }),
+            AnimSignal::Finished(anim) => self.finished.send(AnimationFinished { entity, anim }),
+            AnimSignal::Marker { anim, frame, name } => self.markers.send(FrameMarker {
+                entity,
+                anim,
+                frame,
+                name,
+            }),
+        }
+    }
+}
+
+pub fn update_state_machine(
+    mut requests: EventReader<AnimRequest>,
+    mut query: Query<(Entity, &mut AnimStateMachine, &mut StateGenerator)>,
+    mut events: AnimationEvents,
+) {
+    for request in requests.iter() {
+        if let Ok((_, mut machine, mut generator)) = query.get_mut(request.entity) {
+            machine.request(request.anim, &mut generator);
+        }
+    }
+    for (entity, mut machine, mut generator) in &mut query {
+        machine.update(&mut generator);
+        for signal in machine.take_signals() {
+            events.send(entity, signal);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{animation::UnitSelector, source::MemorySource};
+
+    const MAMODEL: &str = "[modelanim:model2]\n1\n1\n\
+        -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n1000,3600,1000\n";
+
+    fn maanim(len: i32) -> String {
+        format!("[modelanim:animation2]\n1\n1\n0,4,-1,0,0\n2\n0,0,0,0\n{len},100,0,0\n")
+    }
+
+    #[test]
+    fn transitions() {
+        use AnimSelector::*;
+        let selector = UnitSelector::Enemy(3);
+        let mut source = MemorySource::new();
+        source
+            .insert(selector.mamodels(), MAMODEL)
+            .insert(selector.maanim(Walk), maanim(8))
+            .insert(selector.maanim(Attack), maanim(10))
+            .insert(selector.maanim(HitBack), maanim(4));
+        let tracks = UnitTracks::build(&source, selector).unwrap();
+        let models = selector.load_mamodel(&source).unwrap();
+        let mut machine = AnimStateMachine::new(tracks, models);
+        let mut generator = machine.generator();
+        assert_eq!(machine.current(), Walk);
+
+        // Walkはループし続ける
+        generator.seek(100.);
+        assert_eq!(machine.update(&mut generator), None);
+        assert!(!machine.request(BurrowUp, &mut generator));
+        assert!(machine.request(Attack, &mut generator));
+        assert_eq!(generator.time(), 0.);
+        assert!(!machine.request(Walk, &mut generator));
+
+        generator.seek(9.5);
+        assert_eq!(machine.update(&mut generator), None);
+        // Idleが無いのでWalkに戻る
+        generator.seek(10.);
+        assert_eq!(machine.update(&mut generator), Some(Walk));
+
+        generator.seek(3.);
+        assert!(machine.request(HitBack, &mut generator));
+        assert_eq!(machine.current(), HitBack);
+        generator.set_reverse(true);
+        generator.seek(4.);
+        assert_eq!(machine.update(&mut generator), None);
+        generator.set_reverse(false);
+        assert_eq!(machine.update(&mut generator), Some(Walk));
+
+        // Burrowのアニメーションが無ければ受け付けずWalkのまま
+        generator.seek(2.);
+        assert!(!machine.request(BurrowDown, &mut generator));
+        assert_eq!(machine.current(), Walk);
+        assert_eq!(generator.time(), 2.);
+    }
+
+    #[test]
+    fn signals() {
+        use AnimSelector::*;
+        let selector = UnitSelector::Enemy(3);
+        let mut source = MemorySource::new();
+        source
+            .insert(selector.mamodels(), MAMODEL)
+            .insert(selector.maanim(Walk), maanim(8))
+            .insert(selector.maanim(Attack), maanim(10));
+        let tracks = UnitTracks::build(&source, selector).unwrap();
+        let models = selector.load_mamodel(&source).unwrap();
+        let mut machine = AnimStateMachine::new(tracks, models)
+            .with_marker(Attack, 4, "hit")
+            .with_marker(Walk, 0, "step");
+        let mut generator = machine.generator();
+        let marker = |anim, frame, name: &str| AnimSignal::Marker {
+            anim,
+            frame,
+            name: name.into(),
+        };
+
+        machine.start(Walk, &mut generator);
+        generator.advance(0.5 / 30.);
+        machine.update(&mut generator);
+        assert_eq!(
+            machine.take_signals(),
+            [AnimSignal::Started(Walk), marker(Walk, 0, "step")]
+        );
+        // 2周分進めると2回ループして、8と16のマーカーも2回送る
+        generator.seek(16.5);
+        machine.update(&mut generator);
+        assert_eq!(
+            machine.take_signals(),
+            [
+                AnimSignal::Looped(Walk),
+                marker(Walk, 0, "step"),
+                AnimSignal::Looped(Walk),
+                marker(Walk, 0, "step")
+            ]
+        );
+        // 40ちょうどの境目は送るが、40のマーカーはまだ
+        generator.seek(40.);
+        machine.update(&mut generator);
+        let step = marker(Walk, 0, "step");
+        let looped = AnimSignal::Looped(Walk);
+        assert_eq!(
+            machine.take_signals(),
+            [looped.clone(), step.clone(), looped.clone(), step, looped]
+        );
+
+        machine.request(Attack, &mut generator);
+        generator.seek(3.9);
+        machine.update(&mut generator);
+        assert_eq!(machine.take_signals(), [AnimSignal::Started(Attack)]);
+        generator.seek(12.);
+        machine.update(&mut generator);
+        assert_eq!(
+            machine.take_signals(),
+            [
+                marker(Attack, 4, "hit"),
+                AnimSignal::Finished(Attack),
+                AnimSignal::Started(Walk)
+            ]
+        );
+        generator.seek(5.);
+        machine.update(&mut generator);
+        assert_eq!(machine.take_signals(), [marker(Walk, 0, "step")]);
+        // 戻したときは何も送らない
+        generator.seek(2.);
+        machine.update(&mut generator);
+        assert!(machine.take_signals().is_empty());
+    }
+
+    #[test]
+    fn crossfade() {
+        use AnimSelector::*;
+        let selector = UnitSelector::Enemy(3);
+        let mut source = MemorySource::new();
+        source
+            .insert(selector.mamodels(), MAMODEL)
+            .insert(selector.maanim(Walk), maanim(8))
+            .insert(selector.maanim(Attack), maanim(10));
+        let tracks = UnitTracks::build(&source, selector).unwrap();
+        let models = selector.load_mamodel(&source).unwrap();
+        let mut machine = AnimStateMachine::new(tracks, models).with_transition(0.2);
+        let mut generator = machine.generator();
+        let x = |generator: &StateGenerator| generator.current_state().states[0].x;
+
+        generator.seek(5.);
+        assert_eq!(x(&generator), 62.);
+        generator.pause();
+        machine.request(Attack, &mut generator);
+        // 切り替えた直前の状態から始まる
+        assert!(generator.is_fading());
+        assert_eq!(x(&generator), 62.);
+        generator.advance(0.1);
+        assert_eq!(x(&generator), 31.);
+        generator.advance(0.1);
+        assert!(!generator.is_fading());
+        assert_eq!(x(&generator), 0.);
+    }
+}
@@ -0,0 +1,91 @@
+use bevy::{
+    asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+};
+
+use super::{animation::state_gen::Maanim, Imgcut, ImgcutSheet, Mamodels};
+
+/// .imgcut, .mamodel, .maanimをAssetServerから読み込めるようにする
+pub struct BcAssetPlugin;
+
+impl Plugin for BcAssetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<ImgcutSheet>()
+            .add_asset::<Mamodels>()
+            .add_asset::<Maanim>()
+            .init_asset_loader::<ImgcutLoader>()
+            .init_asset_loader::<MamodelLoader>()
+            .init_asset_loader::<MaanimLoader>();
+    }
+}
+
+#[derive(Default)]
+pub struct ImgcutLoader;
+
+impl AssetLoader for ImgcutLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let (filename, imgcuts) = Imgcut::from_reader(bytes)?;
+            // 画像はimgcutと同じディレクトリにある
+            let image_path = AssetPath::new(load_context.path().with_file_name(&filename), None);
+            let texture = load_context.get_handle(image_path.clone());
+            load_context.set_default_asset(
+                LoadedAsset::new(ImgcutSheet {
+                    filename,
+                    texture,
+                    imgcuts,
+                })
+                .with_dependency(image_path),
+            );
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["imgcut"]
+    }
+}
+
+#[derive(Default)]
+pub struct MamodelLoader;
+
+impl AssetLoader for MamodelLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            load_context.set_default_asset(LoadedAsset::new(Mamodels::from_reader(bytes)?));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["mamodel"]
+    }
+}
+
+#[derive(Default)]
+pub struct MaanimLoader;
+
+impl AssetLoader for MaanimLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            load_context.set_default_asset(LoadedAsset::new(Maanim::from_reader(bytes)?));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["maanim"]
+    }
+}
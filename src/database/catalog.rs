@@ -0,0 +1,330 @@
+//! ソースにあるユニット・敵・形態・アニメーションの一覧
+
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap},
+    fs,
+    hash::{Hash, Hasher},
+    path::Path,
+};
+
+use bevy::prelude::Resource;
+use serde::{Deserialize, Serialize};
+
+use super::{
+    animation::{AnimSelector, UnitForm, UnitSelector},
+    error::{Error, ErrorKind},
+    source::AssetSource,
+};
+
+/// 保存する一覧の形式が変わったら上げる
+const INDEX_VERSION: u32 = 2;
+
+/// ビューアーとbc-lintが一覧を保存する場所の既定値
+pub const INDEX_PATH: &str = "assets/cache/catalog.json";
+
+/// ユニット1形態(敵1体)分
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CatalogEntry {
+    pub selector: UnitSelector,
+    /// ファイルがあるアニメーション(AnimSelector::ALLの順)
+    pub anims: Vec<AnimSelector>,
+}
+
+/// ソースを一度だけ走査して作る一覧
+/// mamodel、imgcut、maanimのどれか1つでもあれば載せる
+#[derive(Debug, Clone, Default, PartialEq, Eq, Resource)]
+pub struct UnitCatalog {
+    /// 走査したソースのAssetSource::root
+    root: String,
+    /// 走査したときのファイルの一覧から作った値
+    fingerprint: u64,
+    entries: BTreeMap<UnitSelector, Vec<AnimSelector>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Index {
+    version: u32,
+    root: String,
+    fingerprint: u64,
+    entries: Vec<CatalogEntry>,
+}
+
+/// dir直下の数字の名前のディレクトリ
+fn numbered_dirs(source: &dyn AssetSource, dir: &str) -> Vec<u16> {
+    let mut ids: Vec<u16> = source
+        .read_dir(Path::new(dir))
+        .into_iter()
+        .filter(|path| source.is_dir(path))
+        .filter_map(|path| path.file_name()?.to_str()?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    ids
+}
+
+/// `unit/*/*/`と`enemy/*/`にあるファイルの一覧のハッシュ
+/// ユニットや形態、アニメーションのファイルが増減すると変わる
+fn fingerprint(source: &dyn AssetSource) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    let units = source
+        .read_dir(Path::new("unit"))
+        .into_iter()
+        .flat_map(|dir| source.read_dir(&dir));
+    let enemies = source.read_dir(Path::new("enemy")).into_iter();
+    for dir in units.chain(enemies) {
+        dir.hash(&mut hasher);
+        source.read_dir(&dir).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+impl UnitCatalog {
+    pub fn scan(source: &dyn AssetSource) -> Self {
+        let units = numbered_dirs(source, "unit").into_iter().flat_map(|id| {
+            UnitForm::ALL
+                .into_iter()
+                .map(move |form| UnitSelector::Unit((id, form)))
+        });
+        let enemies = numbered_dirs(source, "enemy")
+            .into_iter()
+            .map(UnitSelector::Enemy);
+        let entries = units
+            .chain(enemies)
+            .filter_map(|selector| {
+                let anims: Vec<_> = AnimSelector::ALL
+                    .into_iter()
+                    .filter(|&anim| source.is_file(Path::new(&selector.maanim(anim))))
+                    .collect();
+                let exists = !anims.is_empty()
+                    || source.is_file(Path::new(&selector.mamodels()))
+                    || source.is_file(Path::new(&selector.imgcuts()));
+                exists.then_some((selector, anims))
+            })
+            .collect();
+        Self {
+            root: source.root(),
+            fingerprint: fingerprint(source),
+            entries,
+        }
+    }
+
+    /// 走査したときからソースが変わっていないか
+    pub fn is_fresh(&self, source: &dyn AssetSource) -> bool {
+        self.root == source.root() && self.fingerprint == fingerprint(source)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// ユニット(番号順、形態順)、敵(番号順)の順
+    pub fn iter(&self) -> impl Iterator<Item = (UnitSelector, &[AnimSelector])> {
+        self.entries
+            .iter()
+            .map(|(selector, anims)| (*selector, anims.as_slice()))
+    }
+
+    pub fn selectors(&self) -> impl Iterator<Item = UnitSelector> + '_ {
+        self.entries.keys().copied()
+    }
+
+    pub fn contains(&self, selector: UnitSelector) -> bool {
+        self.entries.contains_key(&selector)
+    }
+
+    /// 一覧に無ければNone
+    pub fn anims(&self, selector: UnitSelector) -> Option<&[AnimSelector]> {
+        self.entries.get(&selector).map(Vec::as_slice)
+    }
+
+    pub fn has_anim(&self, selector: UnitSelector, anim: AnimSelector) -> bool {
+        self.anims(selector)
+            .is_some_and(|anims| anims.contains(&anim))
+    }
+
+    /// ユニットの番号(重複なし)
+    pub fn units(&self) -> impl Iterator<Item = u16> + '_ {
+        let mut prev = None;
+        self.selectors().filter_map(move |selector| match selector {
+            UnitSelector::Unit((id, _)) if prev != Some(id) => {
+                prev = Some(id);
+                Some(id)
+            }
+            _ => None,
+        })
+    }
+
+    pub fn enemies(&self) -> impl Iterator<Item = u16> + '_ {
+        self.selectors().filter_map(|selector| match selector {
+            UnitSelector::Enemy(id) => Some(id),
+            _ => None,
+        })
+    }
+
+    pub fn forms(&self, id: u16) -> Vec<UnitForm> {
+        UnitForm::ALL
+            .into_iter()
+            .filter(|&form| self.contains(UnitSelector::Unit((id, form))))
+            .collect()
+    }
+
+    pub fn to_json(&self) -> String {
+        let index = Index {
+            version: INDEX_VERSION,
+            root: self.root.clone(),
+            fingerprint: self.fingerprint,
+            entries: self
+                .iter()
+                .map(|(selector, anims)| CatalogEntry {
+                    selector,
+                    anims: anims.to_vec(),
+                })
+                .collect(),
+        };
+        serde_json::to_string_pretty(&index).unwrap()
+    }
+
+    pub fn from_json(s: &str) -> Result<Self, Error> {
+        let index: Index =
+            serde_json::from_str(s).map_err(|e| Error::new(ErrorKind::FileFormatError, e))?;
+        if index.version != INDEX_VERSION {
+            return Err(Error::new(
+                ErrorKind::FileFormatError,
+                format!("一覧のバージョン({})が{INDEX_VERSION}でない", index.version),
+            ));
+        }
+        Ok(Self {
+            root: index.root,
+            fingerprint: index.fingerprint,
+            entries: index
+                .entries
+                .into_iter()
+                .map(|entry| (entry.selector, entry.anims))
+                .collect(),
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::from(e).with_path(parent))?;
+        }
+        fs::write(path, self.to_json()).map_err(|e| Error::from(e).with_path(path))
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        fs::read_to_string(path)
+            .map_err(Error::from)
+            .and_then(|s| Self::from_json(&s))
+            .map_err(|e| e.with_path(path))
+    }
+
+    /// 保存した一覧が同じソースから作ったもので、その後ファイルが増減していなければ読み込む
+    /// そうでなければ走査して保存し直す
+    pub fn load_or_scan<P: AsRef<Path>>(source: &dyn AssetSource, path: P) -> Self {
+        let path = path.as_ref();
+        match Self::load(path) {
+            Ok(catalog) if catalog.is_fresh(source) => catalog,
+            _ => {
+                let catalog = Self::scan(source);
+                if let Err(err) = catalog.save(path) {
+                    println!("{err}");
+                }
+                catalog
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::source::{DirSource, MemorySource};
+
+    #[test]
+    fn scan() {
+        let mut source = MemorySource::new();
+        for selector in [
+            UnitSelector::Unit((1, UnitForm::Form1)),
+            UnitSelector::Unit((1, UnitForm::Form3)),
+            UnitSelector::Unit((0, UnitForm::Form2)),
+            UnitSelector::Enemy(12),
+        ] {
+            source.insert(selector.mamodels(), "");
+            source.insert(selector.maanim(AnimSelector::Walk), "");
+        }
+        let zombie = UnitSelector::Enemy(12);
+        for anim in [
+            AnimSelector::BurrowDown,
+            AnimSelector::BurrowMove,
+            AnimSelector::BurrowUp,
+        ] {
+            source.insert(zombie.maanim(anim), "");
+        }
+        // ユニットのファイルが無いものは載せない
+        source.insert("unit/003/f/readme.txt", "");
+        source.insert("enemy/notes.txt", "");
+        source.insert("unit/002/f/002_f.imgcut", "");
+
+        let catalog = UnitCatalog::scan(&source);
+        assert_eq!(catalog.len(), 5);
+        assert_eq!(catalog.units().collect::<Vec<_>>(), [0, 1, 2]);
+        assert_eq!(catalog.enemies().collect::<Vec<_>>(), [12]);
+        assert_eq!(catalog.forms(1), [UnitForm::Form1, UnitForm::Form3]);
+        assert!(catalog.forms(3).is_empty());
+        assert!(catalog.anims(UnitSelector::Unit((2, UnitForm::Form1))).unwrap().is_empty());
+        assert_eq!(
+            catalog.anims(zombie).unwrap(),
+            [
+                AnimSelector::Walk,
+                AnimSelector::BurrowDown,
+                AnimSelector::BurrowMove,
+                AnimSelector::BurrowUp
+            ]
+        );
+        assert!(!catalog.has_anim(UnitSelector::Unit((0, UnitForm::Form2)), AnimSelector::Idle));
+        assert_eq!(
+            catalog.selectors().next(),
+            Some(UnitSelector::Unit((0, UnitForm::Form2)))
+        );
+
+        let json = catalog.to_json();
+        assert_eq!(UnitCatalog::from_json(&json).unwrap(), catalog);
+        let old = json.replace("\"version\": 2", "\"version\": 1");
+        assert!(UnitCatalog::from_json(&old).is_err());
+    }
+
+    #[test]
+    fn load_or_scan() {
+        let dir = std::env::temp_dir().join(format!("bc-catalog-{}", std::process::id()));
+        let path = dir.join("index/catalog.json");
+        let mut source = MemorySource::new();
+        source.insert(UnitSelector::Enemy(3).mamodels(), "");
+
+        let catalog = UnitCatalog::load_or_scan(&source, &path);
+        assert_eq!(catalog.len(), 1);
+        assert_eq!(UnitCatalog::load(&path).unwrap(), catalog);
+        assert!(catalog.is_fresh(&source));
+        assert_eq!(UnitCatalog::load_or_scan(&source, &path), catalog);
+
+        // ファイルが増えたら走査し直す
+        source.insert(UnitSelector::Unit((5, UnitForm::Form1)).mamodels(), "");
+        assert!(!catalog.is_fresh(&source));
+        let rescanned = UnitCatalog::load_or_scan(&source, &path);
+        assert_eq!(rescanned.len(), 2);
+        assert_eq!(UnitCatalog::load(&path).unwrap(), rescanned);
+
+        // 別のソースの一覧は使わない
+        let other = DirSource::new(dir.join("org"));
+        assert!(!rescanned.is_fresh(&other));
+        assert!(UnitCatalog::load_or_scan(&other, &path).is_empty());
+
+        fs::write(&path, "{").unwrap();
+        assert!(UnitCatalog::load_or_scan(&MemorySource::new(), &path).is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,448 @@
+//! ゲームの.list/.packアーカイブの読み書き
+//!
+//! .listはAES-128-ECBで暗号化された`ファイル数\nname,offset,size\n...`というテキスト。
+//! .packは各ファイルを個別に暗号化(PKCS#7でパディング)して連結したもの。
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use aes::{
+    cipher::{generic_array::GenericArray, BlockDecrypt, BlockEncrypt, KeyInit},
+    Aes128,
+};
+use md5::{Digest, Md5};
+
+use super::{
+    error::{Error, ErrorKind},
+    source::{children, AssetSource},
+};
+
+/// 暗号鍵の地域
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Region {
+    #[default]
+    Jp,
+    En,
+}
+
+/// .packの暗号化方式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PackCipher {
+    /// 暗号化されていない(ImageDataLocal)
+    Plain,
+    Ecb([u8; 16]),
+    Cbc { key: [u8; 16], iv: [u8; 16] },
+}
+
+/// md5(s)の16進表記の先頭16文字
+fn md5_key(s: &str) -> [u8; 16] {
+    let hex = format!("{:x}", Md5::digest(s.as_bytes()));
+    hex.as_bytes()[..16].try_into().unwrap()
+}
+
+fn hex16(s: &str) -> [u8; 16] {
+    let mut bytes = [0; 16];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16).unwrap();
+    }
+    bytes
+}
+
+impl PackCipher {
+    /// .listの暗号化方式
+    pub fn list() -> Self {
+        Self::Ecb(md5_key("pack"))
+    }
+
+    /// パックの名前(拡張子なし)から暗号化方式を決める
+    pub fn for_pack(name: &str, region: Region) -> Self {
+        if name.starts_with("ImageDataLocal") {
+            return Self::Plain;
+        }
+        if name.to_ascii_lowercase().contains("local") {
+            return Self::Ecb(md5_key("battlecats"));
+        }
+        let (key, iv) = match region {
+            Region::Jp => (
+                "d754868de89d717fa9e7b06da45ae9e3",
+                "40b2131a9f388ad4e5002a98118f6128",
+            ),
+            Region::En => (
+                "0ad39e4aeaf55aa717feb1825edef521",
+                "d1d7e708091941d90cdf8aa5f30bb0c2",
+            ),
+        };
+        Self::Cbc {
+            key: hex16(key),
+            iv: hex16(iv),
+        }
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut data = data.to_vec();
+        let (key, mut prev) = match *self {
+            Self::Plain => return Ok(data),
+            Self::Ecb(key) => (key, None),
+            Self::Cbc { key, iv } => (key, Some(iv)),
+        };
+        if !data.len().is_multiple_of(16) {
+            return Err(Error::new(
+                ErrorKind::FileFormatError,
+                format!("暗号化されたデータの長さ({})が16の倍数でない", data.len()),
+            ));
+        }
+        let aes = Aes128::new(GenericArray::from_slice(&key));
+        for block in data.chunks_exact_mut(16) {
+            let encrypted: [u8; 16] = block.try_into().unwrap();
+            aes.decrypt_block(GenericArray::from_mut_slice(block));
+            if let Some(prev) = &mut prev {
+                block.iter_mut().zip(prev.iter()).for_each(|(b, p)| *b ^= p);
+                *prev = encrypted;
+            }
+        }
+        // パディングが正しくなければそのまま返す
+        if let Some(&n) = data.last() {
+            let n = n as usize;
+            if (1..=16).contains(&n)
+                && n <= data.len()
+                && data[data.len() - n..].iter().all(|&b| b as usize == n)
+            {
+                data.truncate(data.len() - n);
+            }
+        }
+        Ok(data)
+    }
+
+    pub fn encrypt(&self, data: &[u8]) -> Vec<u8> {
+        let (key, mut prev) = match *self {
+            Self::Plain => return data.to_vec(),
+            Self::Ecb(key) => (key, None),
+            Self::Cbc { key, iv } => (key, Some(iv)),
+        };
+        let n = 16 - data.len() % 16;
+        let mut data = data.to_vec();
+        data.resize(data.len() + n, n as u8);
+        let aes = Aes128::new(GenericArray::from_slice(&key));
+        for block in data.chunks_exact_mut(16) {
+            if let Some(prev) = &prev {
+                block.iter_mut().zip(prev.iter()).for_each(|(b, p)| *b ^= p);
+            }
+            aes.encrypt_block(GenericArray::from_mut_slice(block));
+            if let Some(prev) = &mut prev {
+                *prev = block.try_into().unwrap();
+            }
+        }
+        data
+    }
+}
+
+/// .listの1行
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PackEntry {
+    pub name: String,
+    offset: usize,
+    size: usize,
+}
+
+/// .listと.packの組
+pub struct Pack {
+    entries: Vec<PackEntry>,
+    data: Vec<u8>,
+    cipher: PackCipher,
+}
+
+impl Pack {
+    /// `<name>.list`と同じディレクトリの`<name>.pack`を読み込む
+    pub fn open<P: AsRef<Path>>(list_path: P, region: Region) -> Result<Self, Error> {
+        let list_path = list_path.as_ref();
+        let pack_path = list_path.with_extension("pack");
+        let name = list_path
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or_default();
+        let list = fs::read(list_path).map_err(|e| Error::from(e).with_path(list_path))?;
+        let data = fs::read(&pack_path).map_err(|e| Error::from(e).with_path(&pack_path))?;
+        Self::from_bytes(&list, data, PackCipher::for_pack(name, region))
+            .map_err(|e| e.with_path(list_path))
+    }
+
+    pub fn from_bytes(list: &[u8], data: Vec<u8>, cipher: PackCipher) -> Result<Self, Error> {
+        let list = PackCipher::list().decrypt(list)?;
+        let list = String::from_utf8(list)
+            .map_err(|e| Error::new(ErrorKind::FileFormatError, e))?;
+        let mut lines = list.lines();
+        let count: usize = lines
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .parse()
+            .map_err(|e| Error::new(ErrorKind::FileFormatError, e).at_line("list", 1))?;
+        let mut entries = Vec::with_capacity(count);
+        for (i, line) in lines.take(count).enumerate() {
+            let line_num = i + 2;
+            let mut split = line.trim_end().split(',');
+            let name = split.next().unwrap_or_default().to_owned();
+            let mut number = |index, field| {
+                split
+                    .next()
+                    .ok_or_else(|| Error::from_kind(ErrorKind::FileFormatError))
+                    .and_then(|s| {
+                        s.trim()
+                            .parse::<usize>()
+                            .map_err(|e| Error::new(ErrorKind::InvalidNumber, e))
+                    })
+                    .map_err(|e| e.at_line("list", line_num).at_field(index, field))
+            };
+            let offset = number(2, "offset")?;
+            let size = number(3, "size")?;
+            if offset.checked_add(size).is_none_or(|end| end > data.len()) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{name}の範囲({offset}+{size})が.packの大きさ({})を超えている", data.len()),
+                )
+                .at_line("list", line_num));
+            }
+            entries.push(PackEntry { name, offset, size });
+        }
+        if entries.len() != count {
+            return Err(Error::new(
+                ErrorKind::FileFormatError,
+                format!("ファイル数({count})だけの行が無い"),
+            ));
+        }
+        Ok(Self {
+            entries,
+            data,
+            cipher,
+        })
+    }
+
+    pub fn entries(&self) -> &[PackEntry] {
+        &self.entries
+    }
+
+    fn read_entry(&self, entry: &PackEntry) -> Result<Vec<u8>, Error> {
+        self.cipher
+            .decrypt(&self.data[entry.offset..entry.offset + entry.size])
+            .map_err(|e| e.with_path(&entry.name))
+    }
+
+    /// 名前が見つからなければNone
+    pub fn read(&self, name: &str) -> Option<Result<Vec<u8>, Error>> {
+        let entry = self.entries.iter().find(|entry| entry.name == name)?;
+        Some(self.read_entry(entry))
+    }
+}
+
+/// .listと.packを書き出す
+pub struct PackWriter {
+    cipher: PackCipher,
+    entries: Vec<PackEntry>,
+    data: Vec<u8>,
+}
+
+impl PackWriter {
+    pub fn new(cipher: PackCipher) -> Self {
+        Self {
+            cipher,
+            entries: Vec::new(),
+            data: Vec::new(),
+        }
+    }
+
+    pub fn add(&mut self, name: &str, bytes: &[u8]) -> &mut Self {
+        let encrypted = self.cipher.encrypt(bytes);
+        self.entries.push(PackEntry {
+            name: name.to_owned(),
+            offset: self.data.len(),
+            size: encrypted.len(),
+        });
+        self.data.extend(encrypted);
+        self
+    }
+
+    /// (.list, .pack)のバイト列
+    pub fn finish(self) -> (Vec<u8>, Vec<u8>) {
+        let mut list = format!("{}\n", self.entries.len());
+        for PackEntry { name, offset, size } in &self.entries {
+            list += &format!("{name},{offset},{size}\n");
+        }
+        (PackCipher::list().encrypt(list.as_bytes()), self.data)
+    }
+}
+
+/// アーカイブ内の平らなファイル名を展開済みのディレクトリ構成でのパスにする
+///
+/// `001_f.mamodel` -> `unit/001/f/001_f.mamodel`、`002_e02.maanim` -> `enemy/002/002_e02.maanim`
+pub fn unit_path(name: &str) -> Option<String> {
+    let (id, rest) = name.split_once('_')?;
+    if id.len() != 3 || !id.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let mut chars = rest.chars();
+    let form = chars.next()?;
+    // 形態の文字の後は拡張子、アニメーション番号、_zombieなど
+    if !chars
+        .next()
+        .is_some_and(|c| c == '.' || c == '_' || c.is_ascii_digit())
+    {
+        return None;
+    }
+    match form {
+        'f' | 'c' | 's' => Some(format!("unit/{id}/{form}/{name}")),
+        'e' => Some(format!("enemy/{id}/{name}")),
+        _ => None,
+    }
+}
+
+/// 複数のパックをまとめて、展開済みのディレクトリ構成のパスで引けるようにしたもの
+#[derive(Default)]
+pub struct PackArchive {
+    packs: Vec<Pack>,
+    /// パス -> (packsの番号, entriesの番号)
+    files: HashMap<PathBuf, (usize, usize)>,
+}
+
+impl PackArchive {
+    /// ディレクトリ直下の全ての.listと.packの組を読み込む
+    pub fn open_dir<P: AsRef<Path>>(dir: P, region: Region) -> Result<Self, Error> {
+        let dir = dir.as_ref();
+        let mut lists: Vec<PathBuf> = fs::read_dir(dir)
+            .map_err(|e| Error::from(e).with_path(dir))?
+            .flatten()
+            .map(|entry| entry.path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "list"))
+            .filter(|path| path.with_extension("pack").is_file())
+            .collect();
+        lists.sort();
+        let mut archive = Self::default();
+        for list in lists {
+            archive.add(Pack::open(list, region)?);
+        }
+        Ok(archive)
+    }
+
+    /// 同じパスのファイルは後から追加したもので上書きされる
+    pub fn add(&mut self, pack: Pack) -> &mut Self {
+        let pack_ind = self.packs.len();
+        for (i, entry) in pack.entries.iter().enumerate() {
+            let path = unit_path(&entry.name).unwrap_or_else(|| entry.name.clone());
+            self.files.insert(PathBuf::from(path), (pack_ind, i));
+        }
+        self.packs.push(pack);
+        self
+    }
+
+    /// アーカイブ内の平らなファイル名で読む
+    pub fn read_name(&self, name: &str) -> Option<Result<Vec<u8>, Error>> {
+        self.packs.iter().rev().find_map(|pack| pack.read(name))
+    }
+}
+
+impl AssetSource for PackArchive {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let &(pack, entry) = self
+            .files
+            .get(path)
+            .ok_or_else(|| Error::from(io::Error::from(io::ErrorKind::NotFound)).with_path(path))?;
+        let pack = &self.packs[pack];
+        pack.read_entry(&pack.entries[entry])
+    }
+
+    fn read_dir(&self, dir: &Path) -> Vec<PathBuf> {
+        children(self.files.keys(), dir)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files
+            .keys()
+            .any(|file| file != path && file.starts_with(path))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{
+        animation::{AnimSelector, UnitForm, UnitSelector},
+        Imgcut,
+    };
+
+    #[test]
+    fn cipher_round_trip() {
+        let data = b"[imgcut]\n0\n001_f.png\n0\n";
+        for cipher in [
+            PackCipher::Plain,
+            PackCipher::list(),
+            PackCipher::for_pack("ImageServer", Region::Jp),
+        ] {
+            let encrypted = cipher.encrypt(data);
+            assert_eq!(cipher.decrypt(&encrypted).unwrap(), data);
+        }
+        assert_eq!(PackCipher::list(), PackCipher::Ecb(*b"b484857901742afc"));
+        assert!(PackCipher::list().decrypt(&[0; 15]).is_err());
+    }
+
+    #[test]
+    fn flat_names() {
+        assert_eq!(unit_path("001_f.mamodel").unwrap(), "unit/001/f/001_f.mamodel");
+        assert_eq!(unit_path("693_c02.maanim").unwrap(), "unit/693/c/693_c02.maanim");
+        assert_eq!(
+            unit_path("010_e_zombie00.maanim").unwrap(),
+            "enemy/010/010_e_zombie00.maanim"
+        );
+        assert_eq!(unit_path("img015_ja.png"), None);
+        assert_eq!(unit_path("001_fx.png"), None);
+    }
+
+    #[test]
+    fn archive() {
+        let mut writer = PackWriter::new(PackCipher::for_pack("ImageServer", Region::Jp));
+        writer
+            .add("001_f.imgcut", b"[imgcut]\n0\n001_f.png\n0\n")
+            .add("001_f.mamodel", b"")
+            .add("002_e.png", b"png")
+            .add(
+                "002_e.mamodel",
+                b"[modelanim:model2]\n1\n1\n-1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n1000,3600,1000\n",
+            )
+            .add("002_e02.maanim", b"[modelanim:animation2]\n1\n0\n");
+        let (list, data) = writer.finish();
+        let pack = Pack::from_bytes(&list, data, PackCipher::for_pack("ImageServer", Region::Jp))
+            .unwrap();
+        assert_eq!(pack.entries().len(), 5);
+        assert_eq!(pack.read("001_f.mamodel").unwrap().unwrap(), b"");
+        assert!(pack.read("003_f.mamodel").is_none());
+
+        let mut archive = PackArchive::default();
+        archive.add(pack);
+        let selector = UnitSelector::Unit((1, UnitForm::Form1));
+        let (filename, _) = Imgcut::load(&archive, selector.imgcuts()).unwrap();
+        assert_eq!(filename, "001_f.png");
+        // UnitSelectorのパスはローダーからそのままアーカイブ内で引ける
+        let enemy = UnitSelector::Enemy(2);
+        assert_eq!(enemy.load_mamodel(&archive).unwrap().models.len(), 1);
+        assert_eq!(enemy.load_maanim(&archive, AnimSelector::Attack).unwrap().period(), 0);
+        assert!(selector.load_mamodel(&archive).is_err());
+        assert_eq!(archive.read(Path::new("enemy/002/002_e.png")).unwrap(), b"png");
+        assert_eq!(archive.read_name("002_e.png").unwrap().unwrap(), b"png");
+        assert!(archive.read(Path::new("002_e.png")).is_err());
+        assert_eq!(archive.read_dir(Path::new("unit")), [PathBuf::from("unit/001")]);
+        assert!(archive.is_dir(Path::new("unit/001/f")));
+    }
+
+    #[test]
+    fn broken_list() {
+        let list = PackCipher::list().encrypt(b"2\n001_f.png,0,16\n");
+        assert!(Pack::from_bytes(&list, vec![0; 16], PackCipher::Plain).is_err());
+        let list = PackCipher::list().encrypt(b"1\n001_f.png,0,32\n");
+        assert!(Pack::from_bytes(&list, vec![0; 16], PackCipher::Plain).is_err());
+    }
+}
@@ -0,0 +1,372 @@
+//! データの読み込み元
+//!
+//! パスは全てソースのルート(展開済みのデータなら`assets/org`)からの相対パスで、
+//! `unit/001/f/001_f.mamodel`のように[`UnitSelector`](super::animation::UnitSelector)の返すパスをそのまま使う。
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bevy::{
+    asset::{AssetIo, AssetIoError, BoxedFuture, FileType, Metadata},
+    prelude::*,
+};
+
+use super::{
+    error::{Error, ErrorKind},
+    pack::{PackArchive, Region},
+};
+
+/// AssetServerの上でソースを置くディレクトリ
+pub const MOUNT: &str = "bc";
+
+pub trait AssetSource: Send + Sync + 'static {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error>;
+
+    /// dir直下のファイルとディレクトリ(ルートからの相対パス)
+    /// ディレクトリが無ければ空
+    fn read_dir(&self, dir: &Path) -> Vec<PathBuf>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn is_dir(&self, path: &Path) -> bool;
+
+    /// どこから読んでいるか(一覧の保存で別のソースと見分けるのに使う)
+    fn root(&self) -> String {
+        String::new()
+    }
+}
+
+fn not_found(path: &Path) -> Error {
+    Error::new(ErrorKind::IOError, io::Error::from(io::ErrorKind::NotFound)).with_path(path)
+}
+
+/// ディレクトリ以下の展開済みのファイル
+#[derive(Debug, Clone)]
+pub struct DirSource {
+    root: PathBuf,
+}
+
+impl DirSource {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self { root: root.into() }
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+impl Default for DirSource {
+    fn default() -> Self {
+        Self::new("assets/org")
+    }
+}
+
+impl AssetSource for DirSource {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        let path = self.root.join(path);
+        fs::read(&path).map_err(|e| Error::from(e).with_path(path))
+    }
+
+    fn read_dir(&self, dir: &Path) -> Vec<PathBuf> {
+        let mut entries: Vec<PathBuf> = fs::read_dir(self.root.join(dir))
+            .into_iter()
+            .flatten()
+            .flatten()
+            .map(|entry| dir.join(entry.file_name()))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.root.join(path).is_file()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.root.join(path).is_dir()
+    }
+
+    fn root(&self) -> String {
+        self.root.display().to_string()
+    }
+}
+
+/// メモリ上のファイル(テスト用)
+#[derive(Debug, Clone, Default)]
+pub struct MemorySource {
+    files: HashMap<PathBuf, Vec<u8>>,
+}
+
+impl MemorySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert<P: Into<PathBuf>>(&mut self, path: P, bytes: impl Into<Vec<u8>>) -> &mut Self {
+        self.files.insert(path.into(), bytes.into());
+        self
+    }
+}
+
+impl AssetSource for MemorySource {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        self.files.get(path).cloned().ok_or_else(|| not_found(path))
+    }
+
+    fn read_dir(&self, dir: &Path) -> Vec<PathBuf> {
+        children(self.files.keys(), dir)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.files
+            .keys()
+            .any(|file| file != path && file.starts_with(path))
+    }
+}
+
+/// ファイルのパスの一覧からdir直下のファイルとディレクトリを取り出す
+pub(crate) fn children<'a>(files: impl Iterator<Item = &'a PathBuf>, dir: &Path) -> Vec<PathBuf> {
+    files
+        .filter_map(|path| {
+            let rest = path.strip_prefix(dir).ok()?;
+            Some(dir.join(rest.components().next()?))
+        })
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// 複数のソースを重ねたもの
+/// 同じパスのファイルは先に追加したソースのものが使われる
+#[derive(Default)]
+pub struct OverlaySource {
+    layers: Vec<Box<dyn AssetSource>>,
+}
+
+impl OverlaySource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, source: impl AssetSource) -> &mut Self {
+        self.layers.push(Box::new(source));
+        self
+    }
+}
+
+impl AssetSource for OverlaySource {
+    fn read(&self, path: &Path) -> Result<Vec<u8>, Error> {
+        match self.layers.iter().find(|layer| layer.is_file(path)) {
+            Some(layer) => layer.read(path),
+            None => Err(not_found(path)),
+        }
+    }
+
+    fn read_dir(&self, dir: &Path) -> Vec<PathBuf> {
+        self.layers
+            .iter()
+            .flat_map(|layer| layer.read_dir(dir))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect()
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.layers.iter().any(|layer| layer.is_file(path))
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        self.layers.iter().any(|layer| layer.is_dir(path))
+    }
+
+    fn root(&self) -> String {
+        self.layers
+            .iter()
+            .map(|layer| layer.root())
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+}
+
+/// アプリ全体で使うソース
+#[derive(Resource, Clone)]
+pub struct BcAssetSource(pub Arc<dyn AssetSource>);
+
+impl BcAssetSource {
+    pub fn new(source: impl AssetSource) -> Self {
+        Self(Arc::new(source))
+    }
+
+    /// 環境変数から作る
+    ///
+    /// - `BC_ASSET_ROOT`: 展開済みのデータのディレクトリ(既定値は`assets/org`)
+    /// - `BC_PACK_DIR`: .list/.packのあるディレクトリ(展開済みのデータより優先する)
+    /// - `BC_PACK_REGION`: `jp`か`en`(既定値は`jp`)
+    pub fn from_env() -> Self {
+        let mut overlay = OverlaySource::new();
+        if let Ok(dir) = std::env::var("BC_PACK_DIR") {
+            let region = match std::env::var("BC_PACK_REGION").as_deref() {
+                Ok("en") => Region::En,
+                _ => Region::Jp,
+            };
+            match PackArchive::open_dir(dir, region) {
+                Ok(archive) => {
+                    overlay.push(archive);
+                }
+                Err(err) => eprintln!("{err}"),
+            }
+        }
+        overlay.push(match std::env::var("BC_ASSET_ROOT") {
+            Ok(root) => DirSource::new(root),
+            Err(_) => DirSource::default(),
+        });
+        Self::new(overlay)
+    }
+
+    /// ソースをMOUNT以下に置いたAssetServer
+    /// DefaultPluginsより先にinsert_resourceする
+    pub fn asset_server(&self) -> AssetServer {
+        let fallback = AssetPlugin::default().create_platform_default_asset_io();
+        AssetServer::new(SourceAssetIo::new(self.0.clone(), MOUNT, fallback))
+    }
+}
+
+impl Default for BcAssetSource {
+    fn default() -> Self {
+        Self::new(DirSource::default())
+    }
+}
+
+impl std::ops::Deref for BcAssetSource {
+    type Target = dyn AssetSource;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref()
+    }
+}
+
+/// ソースの中身をmount以下に置いたように見せるAssetIo
+/// mount以外のパスはfallbackから読む
+pub struct SourceAssetIo {
+    source: Arc<dyn AssetSource>,
+    mount: PathBuf,
+    fallback: Box<dyn AssetIo>,
+}
+
+impl SourceAssetIo {
+    pub fn new<P: Into<PathBuf>>(
+        source: Arc<dyn AssetSource>,
+        mount: P,
+        fallback: Box<dyn AssetIo>,
+    ) -> Self {
+        Self {
+            source,
+            mount: mount.into(),
+            fallback,
+        }
+    }
+
+    fn source_path<'a>(&self, path: &'a Path) -> Option<&'a Path> {
+        path.strip_prefix(&self.mount).ok()
+    }
+}
+
+impl AssetIo for SourceAssetIo {
+    fn load_path<'a>(&'a self, path: &'a Path) -> BoxedFuture<'a, Result<Vec<u8>, AssetIoError>> {
+        Box::pin(async move {
+            let Some(source_path) = self.source_path(path) else {
+                return self.fallback.load_path(path).await;
+            };
+            if !self.source.is_file(source_path) {
+                return Err(AssetIoError::NotFound(path.to_owned()));
+            }
+            self.source
+                .read(source_path)
+                .map_err(|e| AssetIoError::Io(io::Error::new(io::ErrorKind::InvalidData, e)))
+        })
+    }
+
+    fn read_directory(
+        &self,
+        path: &Path,
+    ) -> Result<Box<dyn Iterator<Item = PathBuf>>, AssetIoError> {
+        let Some(source_path) = self.source_path(path) else {
+            return self.fallback.read_directory(path);
+        };
+        if !self.source.is_dir(source_path) {
+            return Err(AssetIoError::NotFound(path.to_owned()));
+        }
+        let mount = self.mount.clone();
+        Ok(Box::new(
+            self.source
+                .read_dir(source_path)
+                .into_iter()
+                .map(move |p| mount.join(p)),
+        ))
+    }
+
+    fn get_metadata(&self, path: &Path) -> Result<Metadata, AssetIoError> {
+        let Some(source_path) = self.source_path(path) else {
+            return self.fallback.get_metadata(path);
+        };
+        if self.source.is_file(source_path) {
+            Ok(Metadata::new(FileType::File))
+        } else if self.source.is_dir(source_path) {
+            Ok(Metadata::new(FileType::Directory))
+        } else {
+            Err(AssetIoError::NotFound(path.to_owned()))
+        }
+    }
+
+    fn watch_path_for_changes(
+        &self,
+        to_watch: &Path,
+        to_reload: Option<PathBuf>,
+    ) -> Result<(), AssetIoError> {
+        self.fallback.watch_path_for_changes(to_watch, to_reload)
+    }
+
+    fn watch_for_changes(&self) -> Result<(), AssetIoError> {
+        self.fallback.watch_for_changes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::database::{animation::UnitSelector, Imgcut, Mamodels};
+
+    #[test]
+    fn overlay() {
+        let selector = UnitSelector::Enemy(2);
+        let mut base = MemorySource::new();
+        base.insert(selector.imgcuts(), "[imgcut]\n0\n002_e.png\n0\n")
+            .insert(
+                selector.mamodels(),
+                "[modelanim:model2]\n1\n1\n-1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n1000,3600,1000\n",
+            );
+        let mut patch = MemorySource::new();
+        patch.insert(selector.imgcuts(), "[imgcut]\n0\npatched.png\n0\n");
+        let mut overlay = OverlaySource::new();
+        overlay.push(patch).push(base);
+
+        let (filename, _) = Imgcut::load(&overlay, selector.imgcuts()).unwrap();
+        assert_eq!(filename, "patched.png");
+        assert_eq!(Mamodels::load(&overlay, selector.mamodels()).unwrap().models.len(), 1);
+        assert_eq!(overlay.read_dir(Path::new("enemy")), [PathBuf::from("enemy/002")]);
+        assert!(overlay.is_dir(Path::new("enemy/002")));
+
+        let err = Mamodels::load(&overlay, "enemy/003/003_e.mamodel").unwrap_err();
+        assert!(err.to_string().starts_with("enemy/003/003_e.mamodel"));
+    }
+}
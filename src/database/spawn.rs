@@ -37,21 +37,22 @@ fn replace_dummy(
 ) {
     for (dummy_unit, id, transform) in &query {
         // spawning character
-        let UnitImage {
+        let Some(UnitImage {
             materials: material_handles,
-            meshes: mesh_handles,
-            size: sizes,
             mamodels,
-        } = images.images[dummy_unit.id.id];
+            ..
+        }) = &images.images[dummy_unit.id.id] else {
+            continue;
+        };
         commands.spawn((Unit, SpatialBundle {
-            transform: transform.clone(),
+            transform: *transform,
             ..default()
         })).with_children(|parent| {
-            let ids = Vec::new();
-            
-            parent.spawn((UnitSpritePartParent, SpatialBundle::default()))
-        })
-        
+            for _ in &mamodels.models {
+                parent.spawn((UnitSpritePartParent, SpatialBundle::default()));
+            }
+        });
+
         let id = id.id;
         commands.entity(id).despawn();
     }
@@ -0,0 +1,332 @@
+//! 読み込んだユニットのデータの整合性チェック
+
+use std::fmt;
+
+use super::{
+    animation::state_gen::{Maanim, Modification},
+    error::{Error, ErrorKind},
+    Imgcut, Mamodels,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// 描画はできるが見た目がおかしくなる
+    Warning,
+    /// 描画中にパニックや無限ループを起こす
+    Error,
+}
+
+/// 問題のある場所
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    /// mamodel全体
+    Model,
+    /// mamodelのパーツ
+    Part(usize),
+    /// anims[anim]のparts[part]
+    Anim { anim: usize, part: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub target: Target,
+    pub message: String,
+}
+
+impl Diagnostic {
+    fn error(target: Target, message: String) -> Self {
+        Self {
+            severity: Severity::Error,
+            target,
+            message,
+        }
+    }
+
+    fn warning(target: Target, message: String) -> Self {
+        Self {
+            severity: Severity::Warning,
+            target,
+            message,
+        }
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        })
+    }
+}
+
+impl fmt::Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Model => f.write_str("mamodel"),
+            Target::Part(i) => write!(f, "mamodel part {i}"),
+            Target::Anim { anim, part } => write!(f, "maanim {anim} part {part}"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {}: {}", self.severity, self.target, self.message)
+    }
+}
+
+/// mamodel、imgcut、maanimの間の整合性をチェックする
+pub fn validate(models: &Mamodels, imgcuts: &[Imgcut], anims: &[Maanim]) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    validate_model(models, imgcuts, &mut diagnostics);
+    for (i, anim) in anims.iter().enumerate() {
+        validate_anim(i, anim, models, imgcuts, &mut diagnostics);
+    }
+    diagnostics
+}
+
+/// Errorが1つでもあればErrorKind::InvalidDataとして返す
+/// 問題がなければWarningだけを返す
+pub fn check(
+    models: &Mamodels,
+    imgcuts: &[Imgcut],
+    anims: &[Maanim],
+) -> Result<Vec<Diagnostic>, Error> {
+    let diagnostics = validate(models, imgcuts, anims);
+    let errors: Vec<String> = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .map(Diagnostic::to_string)
+        .collect();
+    if errors.is_empty() {
+        Ok(diagnostics)
+    } else {
+        Err(Error::new(ErrorKind::InvalidData, errors.join("\n")))
+    }
+}
+
+fn validate_model(models: &Mamodels, imgcuts: &[Imgcut], diagnostics: &mut Vec<Diagnostic>) {
+    let len = models.models.len();
+    if len == 0 {
+        diagnostics.push(Diagnostic::error(Target::Model, "パーツが無い".into()));
+    }
+    for (name, ratio) in [
+        ("scale", models.scale_ratio),
+        ("angle", models.angle_ratio),
+        ("opacity", models.opacity_ratio),
+    ] {
+        if ratio == 0 {
+            diagnostics.push(Diagnostic::error(
+                Target::Model,
+                format!("{name}の比率が0"),
+            ));
+        }
+    }
+
+    for (i, model) in models.models.iter().enumerate() {
+        if i == 0 {
+            if model.parent >= 0 {
+                diagnostics.push(Diagnostic::error(
+                    Target::Part(0),
+                    format!("ルートパーツに親({})がある", model.parent),
+                ));
+            }
+        } else if model.parent < 0 || model.parent as usize >= len {
+            diagnostics.push(Diagnostic::error(
+                Target::Part(i),
+                format!("親の番号({})がパーツ数({len})の範囲外", model.parent),
+            ));
+        }
+        // 2枚目以降のシート(id > 0)のimgcutは渡されないので調べない
+        if model.id <= 0 && model.imgind as usize >= imgcuts.len() && model.imgind >= 0 {
+            diagnostics.push(Diagnostic::warning(
+                Target::Part(i),
+                format!("画像の番号({})がimgcut数({})の範囲外", model.imgind, imgcuts.len()),
+            ));
+        }
+    }
+
+    for i in parent_cycle(models) {
+        diagnostics.push(Diagnostic::error(
+            Target::Part(i),
+            "親の参照が循環している".into(),
+        ));
+    }
+}
+
+/// 親をたどると自分自身に戻ってくるパーツ
+fn parent_cycle(models: &Mamodels) -> Vec<usize> {
+    let len = models.models.len();
+    let parent = |i: usize| {
+        let p = models.models[i].parent;
+        (0..len as i32).contains(&p).then_some(p as usize)
+    };
+    (0..len)
+        .filter(|&i| {
+            let mut current = parent(i);
+            // len回たどって戻ってこなければ循環していない
+            for _ in 0..len {
+                match current {
+                    Some(p) if p == i => return true,
+                    Some(p) => current = parent(p),
+                    None => return false,
+                }
+            }
+            false
+        })
+        .collect()
+}
+
+fn validate_anim(
+    anim_ind: usize,
+    anim: &Maanim,
+    models: &Mamodels,
+    imgcuts: &[Imgcut],
+    diagnostics: &mut Vec<Diagnostic>,
+) {
+    let len = models.models.len();
+    for (i, part) in anim.parts().iter().enumerate() {
+        let target = Target::Anim {
+            anim: anim_ind,
+            part: i,
+        };
+        let id = part.id() as usize;
+        if id >= len {
+            diagnostics.push(Diagnostic::error(
+                target,
+                format!("パーツの番号({id})がパーツ数({len})の範囲外"),
+            ));
+        }
+        // 同じフレームが続くのは値を瞬時に切り替えるときに使われるので許す
+        if part
+            .eases()
+            .windows(2)
+            .any(|w| w[0].frame() > w[1].frame())
+        {
+            diagnostics.push(Diagnostic::error(
+                target,
+                "キーフレームが昇順に並んでいない".into(),
+            ));
+        }
+        match part.modification() {
+            Modification::Parent => {
+                for ease in part.eases() {
+                    let v = ease.value();
+                    if v < 0 || v as usize >= len || v as usize == id {
+                        diagnostics.push(Diagnostic::error(
+                            target,
+                            format!("フレーム{}の親の番号({v})が不正", ease.frame()),
+                        ));
+                    }
+                }
+            }
+            Modification::Sprite => {
+                for ease in part.eases() {
+                    let v = ease.value();
+                    if v >= 0 && v as usize >= imgcuts.len() {
+                        diagnostics.push(Diagnostic::warning(
+                            target,
+                            format!(
+                                "フレーム{}の画像の番号({v})がimgcut数({})の範囲外",
+                                ease.frame(),
+                                imgcuts.len()
+                            ),
+                        ));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAMODEL: &str = "[modelanim:model2]\n1\n4\n\
+        -1,-1,0,0,0,0,0,0,1000,1000,0,1000,0\n\
+        0,0,1,1,0,0,0,0,1000,1000,0,1000,0\n\
+        3,0,5,2,0,0,0,0,1000,1000,0,1000,0\n\
+        2,0,0,3,0,0,0,0,1000,1000,0,1000,0\n\
+        1000,3600,1000\n";
+    const IMGCUT: &str = "[imgcut]\n0\n000_f.png\n2\n0,0,8,8\n8,0,8,8\n";
+
+    #[test]
+    fn model() {
+        let models: Mamodels = MAMODEL.parse().unwrap();
+        let (_, imgcuts) = Imgcut::from_str(IMGCUT).unwrap();
+        let diagnostics = validate(&models, &imgcuts, &[]);
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| (d.severity, d.target))
+                .collect::<Vec<_>>(),
+            [
+                (Severity::Warning, Target::Part(2)),
+                (Severity::Error, Target::Part(2)),
+                (Severity::Error, Target::Part(3)),
+            ]
+        );
+        assert!(check(&models, &imgcuts, &[]).is_err());
+    }
+
+    #[test]
+    fn anim() {
+        let models: Mamodels = MAMODEL.replace("\n3,0,5", "\n1,0,1").parse().unwrap();
+        let (_, imgcuts) = Imgcut::from_str(IMGCUT).unwrap();
+        let anim: Maanim = "[modelanim:animation2]\n1\n2\n\
+            9,4,-1,0,0\n2\n0,0,0,0\n10,5,0,0\n\
+            1,0,-1,0,0\n2\n5,0,0,0\n0,1,0,0\n"
+            .parse()
+            .unwrap();
+        let diagnostics = validate(&models, &imgcuts, &[anim]);
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| (d.severity, d.target))
+                .collect::<Vec<_>>(),
+            [
+                (
+                    Severity::Error,
+                    Target::Anim { anim: 0, part: 0 }
+                ),
+                (
+                    Severity::Error,
+                    Target::Anim { anim: 0, part: 0 }
+                ),
+                (
+                    Severity::Error,
+                    Target::Anim { anim: 0, part: 1 }
+                ),
+            ]
+        );
+        assert!(check(&models, &imgcuts, &[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn same_frame() {
+        use crate::database::{
+            animation::{state_gen::cache::UnitTracks, AnimSelector, UnitSelector},
+            source::MemorySource,
+        };
+        // 5フレーム目で0から100に切り替える
+        let maanim = "[modelanim:animation2]\n1\n1\n\
+            1,4,-1,0,0\n4\n0,0,0,0\n5,0,0,0\n5,100,0,0\n10,100,0,0\n";
+        let models: Mamodels = MAMODEL.replace("\n3,0,5", "\n1,0,1").parse().unwrap();
+        let anim: Maanim = maanim.parse().unwrap();
+        assert!(validate(&models, &[], &[anim])
+            .iter()
+            .all(|d| !matches!(d.target, Target::Anim { .. })));
+
+        let selector = UnitSelector::Enemy(1);
+        let mut source = MemorySource::new();
+        source
+            .insert(selector.mamodels(), MAMODEL.replace("\n3,0,5", "\n1,0,1"))
+            .insert(selector.maanim(AnimSelector::Walk), maanim);
+        let tracks = UnitTracks::build(&source, selector).unwrap();
+        assert!(tracks.get(AnimSelector::Walk).is_some());
+    }
+}
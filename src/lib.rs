@@ -0,0 +1,4 @@
+#![allow(dead_code)]
+
+pub mod database;
+pub mod material;
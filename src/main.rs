@@ -1,12 +1,14 @@
 #![allow(dead_code)]
 
-mod database;
-mod material;
-use std::time::Duration;
+mod cli;
+
+use std::process::ExitCode;
+
+use battle_cats::{database, material};
 
 use bevy::{
     prelude::*,
-    sprite::{Anchor, Material2dPlugin},
+    sprite::Anchor,
 };
 
 #[derive(Component)]
@@ -36,7 +38,7 @@ fn startup_system(
     commands.spawn(Camera2dBundle::default());
     let texture = asset_server.load("org/enemy/000/000_e.png");
 
-    let mut sprite = SpriteBundle {
+    let sprite = SpriteBundle {
         texture,
         sprite: Sprite {
             color: Color::rgba(1., 0., 1., 1.),
@@ -51,7 +53,7 @@ fn startup_system(
         ..default()
     };
 
-    let mut sprite2 = SpriteBundle {
+    let sprite2 = SpriteBundle {
         transform: Transform::from_xyz(9., -7., -1.)
             .with_scale(Vec3::new(1., 1., 1.))
             .with_rotation(Quat::from_rotation_z(f32::to_radians(-15.8))),
@@ -98,7 +100,7 @@ fn animate_system(mut query: Query<&mut TextureAtlasSprite>) {
 fn toggle_child(
     mut entities: ResMut<Entities>,
     mut commands: Commands,
-    mut query: Query<&mut Transform>,
+    _query: Query<&mut Transform>,
     grandchild: Res<GrandChild>,
     input: Res<Input<KeyCode>>,
 ) {
@@ -128,18 +130,30 @@ fn draw_alpha(mut commands: Commands, server: Res<AssetServer>) {
     });
 }
 
-fn main() {
+fn main() -> ExitCode {
+    // サブコマンドがあればウィンドウを開かずに実行する
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        return cli::run(&args);
+    }
+    // データの場所は環境変数で切り替える (BcAssetSource::from_env)
+    let source = database::source::BcAssetSource::from_env();
     App::new()
+        .insert_resource(source.asset_server())
+        .insert_resource(source)
+        .insert_resource(database::animation::UnitRenderer::from_env())
         .add_plugins(DefaultPlugins)
         // .add_plugin(BattleCatsUnit)
         // .add_startup_system(startup_system)
         // .add_startup_system(draw_alpha)
         // .add_system(test_system)
         // .add_system(toggle_child)
+        .add_plugin(database::asset_loader::BcAssetPlugin)
         .add_plugin(database::animation::PluginTemp)
         .insert_resource(ClearColor(Color::GRAY))
-        .add_plugin(Material2dPlugin::<material::Glow1Material>::default())
+        .add_plugin(material::Glow1MaterialPlugin)
         // .add_startup_system(material::startup)
         // .add_system(material::system)
         .run();
+    ExitCode::SUCCESS
 }
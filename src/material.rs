@@ -1,5 +1,6 @@
 #![allow(dead_code)]
 use bevy::{
+    asset::load_internal_asset,
     prelude::*,
     reflect::TypeUuid,
     render::{
@@ -9,17 +10,112 @@ use bevy::{
             RenderPipelineDescriptor, SpecializedMeshPipelineError, ShaderRef,
         },
     },
-    sprite::{Material2d, Material2dKey, MaterialMesh2dBundle, COLOR_MATERIAL_SHADER_HANDLE, ColorMaterialUniform},
+    sprite::{Material2d, Material2dKey, Material2dPlugin, MaterialMesh2dBundle, ColorMaterialUniform},
 };
 
+use crate::database::GlowType;
+
+pub const GLOW_MATERIAL_SHADER_HANDLE: HandleUntyped =
+    HandleUntyped::weak_from_u64(Shader::TYPE_UUID, 8317702385532201613);
+
+/// Glow1Materialのシェーダーとマテリアルを登録する
+pub struct Glow1MaterialPlugin;
+
+impl Plugin for Glow1MaterialPlugin {
+    fn build(&self, app: &mut App) {
+        load_internal_asset!(
+            app,
+            GLOW_MATERIAL_SHADER_HANDLE,
+            "material.wgsl",
+            Shader::from_wgsl
+        );
+        app.add_plugin(Material2dPlugin::<Glow1Material>::default());
+    }
+}
+
+/// 通常の半透明以外の合成をするパーツのマテリアル
+/// 合成方法はmodeで選ぶ(GlowType::Noneの場合は通常の半透明)
 #[derive(TypeUuid, Clone, AsBindGroup, Debug)]
 #[uuid = "9548cd40-3262-4c8f-ad68-b4d778be26f0"]
 #[uniform(0, ColorMaterialUniform)]
+#[bind_group_data(Glow1MaterialKey)]
 pub struct Glow1Material {
     pub color: Color,
     #[texture(1)]
     #[sampler(2)]
     pub texture: Option<Handle<Image>>,
+    pub mode: GlowType,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Glow1MaterialKey {
+    mode: GlowType,
+}
+
+impl From<&Glow1Material> for Glow1MaterialKey {
+    fn from(material: &Glow1Material) -> Self {
+        Self {
+            mode: material.mode,
+        }
+    }
+}
+
+impl Glow1Material {
+    pub fn new(texture: Handle<Image>, mode: GlowType) -> Self {
+        Self {
+            color: Color::default(),
+            texture: Some(texture),
+            mode,
+        }
+    }
+}
+
+/// シェーダーで色にアルファを掛けてから合成するか
+/// Inverseは係数に(1 - dst)を使うので、アルファは色の方に入れる
+fn premultiplies(mode: GlowType) -> bool {
+    mode == GlowType::Inverse
+}
+
+/// - Black: 加算(明るくなる)
+/// - White: 減算(暗くなる)
+/// - Inverse: 不透明な部分で下の色を反転する(色はpremultipliesで乗算済み)
+fn blend_state(mode: GlowType) -> BlendState {
+    let keep_alpha = BlendComponent {
+        src_factor: BlendFactor::Zero,
+        dst_factor: BlendFactor::One,
+        operation: BlendOperation::Add,
+    };
+    match mode {
+        GlowType::None => BlendState::ALPHA_BLENDING,
+        GlowType::Black => BlendState {
+            color: BlendComponent {
+                src_factor: BlendFactor::SrcAlpha,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::Add,
+            },
+            alpha: BlendComponent {
+                src_factor: BlendFactor::SrcAlpha,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::Add,
+            },
+        },
+        GlowType::White => BlendState {
+            color: BlendComponent {
+                src_factor: BlendFactor::SrcAlpha,
+                dst_factor: BlendFactor::One,
+                operation: BlendOperation::ReverseSubtract,
+            },
+            alpha: keep_alpha,
+        },
+        GlowType::Inverse => BlendState {
+            color: BlendComponent {
+                src_factor: BlendFactor::OneMinusDst,
+                dst_factor: BlendFactor::OneMinusSrcAlpha,
+                operation: BlendOperation::Add,
+            },
+            alpha: keep_alpha,
+        },
+    }
 }
 
 impl bevy::render::render_resource::AsBindGroupShaderType<ColorMaterialUniform> for Glow1Material {
@@ -38,27 +134,19 @@ impl bevy::render::render_resource::AsBindGroupShaderType<ColorMaterialUniform>
 
 impl Material2d for Glow1Material {
     fn fragment_shader() -> ShaderRef {
-        COLOR_MATERIAL_SHADER_HANDLE.typed().into()
+        GLOW_MATERIAL_SHADER_HANDLE.typed().into()
     }
     fn specialize(
         descriptor: &mut RenderPipelineDescriptor,
         _layout: &MeshVertexBufferLayout,
-        _key: Material2dKey<Self>,
+        key: Material2dKey<Self>,
     ) -> Result<(), SpecializedMeshPipelineError> {
         if let Some(fragment) = &mut descriptor.fragment {
+            if premultiplies(key.bind_group_data.mode) {
+                fragment.shader_defs.push("PREMULTIPLY_ALPHA".into());
+            }
             if let Some(target_state) = &mut fragment.targets[0] {
-                target_state.blend = Some(BlendState {
-                    color: BlendComponent {
-                        src_factor: BlendFactor::SrcAlpha,
-                        dst_factor: BlendFactor::One,
-                        operation: BlendOperation::Add,
-                    },
-                    alpha: BlendComponent {
-                        src_factor: BlendFactor::SrcAlpha,
-                        dst_factor: BlendFactor::One,
-                        operation: BlendOperation::Add,
-                    },
-                });
+                target_state.blend = Some(blend_state(key.bind_group_data.mode));
             }
         }
         Ok(())
@@ -67,10 +155,7 @@ impl Material2d for Glow1Material {
 
 impl From<Handle<Image>> for Glow1Material {
     fn from(value: Handle<Image>) -> Self {
-        Self {
-            color: Color::default(),
-            texture: Some(value),
-        }
+        Self::new(value, GlowType::Black)
     }
 }
 
@@ -92,6 +177,7 @@ pub fn startup(
             material: materials.add(Glow1Material {
                 color: Color::rgba(1., 1., 1., 0.5),
                 texture: Some(server.load("org/unit/693/f/693_f.png")),
+                mode: GlowType::Black,
             }),
             transform: Transform::from_xyz(0., 0., 1.).with_scale(Vec3::new(300., 200., 0.)),
             ..default()
@@ -114,6 +200,53 @@ mod test {
     #[test]
     fn typeid() {}
 
+    #[test]
+    fn blend_modes() {
+        let modes = [GlowType::None, GlowType::Black, GlowType::White, GlowType::Inverse];
+        let states: Vec<_> = modes.into_iter().map(blend_state).collect();
+        for (i, a) in states.iter().enumerate() {
+            assert!(states[i + 1..].iter().all(|b| a != b));
+        }
+        assert_eq!(states[2].color.operation, BlendOperation::ReverseSubtract);
+        assert_eq!(states[3].color.src_factor, BlendFactor::OneMinusDst);
+    }
+
+    /// 合成後の色(シェーダーの出力srcはアルファが掛かっていない色)
+    fn blend(mode: GlowType, src: Vec4, dst: Vec3) -> Vec3 {
+        let color = blend_state(mode).color;
+        let src_rgb = if premultiplies(mode) { src.truncate() * src.w } else { src.truncate() };
+        let factor = |factor| match factor {
+            BlendFactor::Zero => Vec3::ZERO,
+            BlendFactor::One => Vec3::ONE,
+            BlendFactor::SrcAlpha => Vec3::splat(src.w),
+            BlendFactor::OneMinusSrcAlpha => Vec3::splat(1. - src.w),
+            BlendFactor::OneMinusDst => Vec3::ONE - dst,
+            _ => unimplemented!(),
+        };
+        let (a, b) = (src_rgb * factor(color.src_factor), dst * factor(color.dst_factor));
+        match color.operation {
+            BlendOperation::Add => a + b,
+            BlendOperation::ReverseSubtract => b - a,
+            _ => unimplemented!(),
+        }
+    }
+
+    #[test]
+    fn inverse_opacity() {
+        let dst = Vec3::new(0.2, 0.6, 1.);
+        let white = |alpha| Vec4::new(1., 1., 1., alpha);
+        assert!(blend(GlowType::Inverse, white(1.), dst).abs_diff_eq(Vec3::ONE - dst, 1e-6));
+        // 不透明度0や透明な画素は下の色を変えない
+        assert!(blend(GlowType::Inverse, white(0.), dst).abs_diff_eq(dst, 1e-6));
+        assert!(blend(GlowType::Inverse, Vec4::new(1., 0., 1., 0.), dst).abs_diff_eq(dst, 1e-6));
+        // 半分なら反転した色と元の色の中間(白で反転すると0.5になる)
+        assert!(blend(GlowType::Inverse, white(0.5), dst).abs_diff_eq(Vec3::splat(0.5), 1e-6));
+        let red = Vec4::new(1., 0., 0., 0.5);
+        let expected = (Vec3::X * (Vec3::ONE - dst) + dst) / 2.;
+        assert!(blend(GlowType::Inverse, red, dst).abs_diff_eq(expected, 1e-6));
+        assert!(blend(GlowType::Black, white(0.5), dst).abs_diff_eq(dst + 0.5, 1e-6));
+    }
+
     #[test]
     fn material() {
         App::new()
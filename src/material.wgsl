@@ -0,0 +1,46 @@
+// bevy_spriteのcolor_material.wgslに、Inverseのための乗算済みアルファを足したもの
+#import bevy_sprite::mesh2d_types
+#import bevy_sprite::mesh2d_view_bindings
+
+#ifdef TONEMAP_IN_SHADER
+#import bevy_core_pipeline::tonemapping
+#endif
+
+struct ColorMaterial {
+    color: vec4<f32>,
+    flags: u32,
+};
+const COLOR_MATERIAL_FLAGS_TEXTURE_BIT: u32 = 1u;
+
+@group(1) @binding(0)
+var<uniform> material: ColorMaterial;
+@group(1) @binding(1)
+var texture: texture_2d<f32>;
+@group(1) @binding(2)
+var texture_sampler: sampler;
+
+@group(2) @binding(0)
+var<uniform> mesh: Mesh2d;
+
+struct FragmentInput {
+    #import bevy_sprite::mesh2d_vertex_output
+};
+
+@fragment
+fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
+    var output_color: vec4<f32> = material.color;
+#ifdef VERTEX_COLORS
+    output_color = output_color * in.color;
+#endif
+    if ((material.flags & COLOR_MATERIAL_FLAGS_TEXTURE_BIT) != 0u) {
+        output_color = output_color * textureSample(texture, texture_sampler, in.uv);
+    }
+#ifdef TONEMAP_IN_SHADER
+    output_color = tone_mapping(output_color);
+#endif
+#ifdef PREMULTIPLY_ALPHA
+    // 合成の係数にアルファを入れられないので、色に掛けておく
+    output_color = vec4<f32>(output_color.rgb * output_color.a, output_color.a);
+#endif
+    return output_color;
+}